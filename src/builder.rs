@@ -0,0 +1,135 @@
+use crate::Options;
+use crate::corpus;
+use crate::tokenizer::Tokenizer;
+use crate::completion::{self, Forms, Words};
+use crate::prediction::{self, Counts, MappedModel, Model};
+use crate::statistics::Statistics;
+use crate::cache::{self, Rebuild};
+use crate::error::Result;
+use std::path::Path;
+
+/// Loads the completion and prediction models of a language from their caches,
+/// and rebuilds the outdated ones from a single scan of the corpus of the language,
+/// which can also collect the corpus statistics.
+pub struct ModelBuilder<'o> {
+    options: &'o Options,
+    language: &'o str,
+    rebuild: Rebuild,
+    statistics: Option<&'o Path>,
+}
+
+/// The completion and prediction models of a language, ready for use.
+pub struct Models {
+    pub words: Words,
+    pub prediction: MappedModel,
+}
+
+/// Everything that is counted during the scan, by a single worker.
+struct Scan {
+    counts: Counts,
+    forms: Forms,
+    statistics: Option<Statistics>,
+}
+
+impl<'o> ModelBuilder<'o> {
+    pub fn new(options: &'o Options, language: &'o str, rebuild: Rebuild) -> Self {
+        ModelBuilder { options, language, rebuild, statistics: None }
+    }
+
+    /// Also write the statistics reports into the output directory, which always requires a scan of the corpus.
+    pub fn statistics(self, output: &'o Path) -> Self {
+        ModelBuilder { statistics: Some(output), .. self }
+    }
+
+    pub fn build(self) -> Result<Models> {
+        let options = self.options;
+        let corpus = options.corpus.language(self.language);
+        let completion_cache = cache::language_path(&options.completion_cache, self.language);
+        let prediction_cache = cache::language_path(&options.prediction_cache, self.language);
+
+        println!("attempting to load completion cache {}...", completion_cache.display());
+        let (words, completion_manifest) = completion::load(&corpus, &completion_cache, &*options.tokenizer, options.max_edit_distance, self.rebuild)?;
+
+        println!("attempting to load prediction cache {}...", prediction_cache.display());
+        let (model, prediction_manifest) = prediction::load(&corpus, &prediction_cache, options.order, &options.pruning, &*options.tokenizer, self.rebuild)?;
+
+        let (words, model) = match (words, model, self.statistics) {
+            (Some(words), Some(model), None) => {
+                println!("... loaded caches");
+                (words, model)
+            },
+
+            (words, model, statistics) => {
+                // the word counts are always collected, but the chains only if they are needed
+                let max_chain_len = if model.is_none() { options.order } else { 0 };
+                let collect_statistics = statistics.is_some();
+                let tokenizer = &*options.tokenizer;
+
+                println!("... scanning corpus");
+
+                let Scan { counts, forms, statistics: scanned } = corpus::fold_sentences(
+                    &corpus, || Scan::new(collect_statistics),
+                    |scan, weight, sentence| scan.add(max_chain_len, tokenizer, weight, &sentence),
+                    Scan::merge
+                )?;
+
+                let words = words.map(Ok).unwrap_or_else(|| {
+                    println!("computing new completion cache");
+                    let words = Words::new(counts.words(), &forms, options.max_edit_distance);
+                    completion::store(&completion_cache, completion_manifest, &words, tokenizer, options.max_edit_distance).map(|_| words)
+                })?;
+
+                let model = model.map(Ok).unwrap_or_else(|| {
+                    println!("computing new prediction cache");
+                    let mut model = Model::new(options.order);
+                    model.add(counts)?;
+                    model.prune(&options.pruning);
+                    prediction::store(&prediction_cache, prediction_manifest, &model, &options.pruning, tokenizer)
+                })?;
+
+                if let (Some(output), Some(statistics)) = (statistics, scanned) {
+                    statistics.write(output)?;
+                }
+
+                (words, model)
+            },
+        };
+
+        Ok(Models { words, prediction: model })
+    }
+}
+
+impl Scan {
+    fn new(collect_statistics: bool) -> Self {
+        Scan {
+            counts: Counts::new(),
+            forms: Forms::new(),
+            statistics: if collect_statistics { Some(Statistics::new()) } else { None },
+        }
+    }
+
+    /// Split the sentence into words once, and count it for every model.
+    fn add(&mut self, max_chain_len: usize, tokenizer: &dyn Tokenizer, weight: usize, sentence: &str) {
+        let surface_words = tokenizer.surface_words(sentence);
+        let words: Vec<String> = surface_words.iter().map(|word| tokenizer.fold_case(word)).collect();
+
+        self.counts.add(max_chain_len, weight, sentence, &words);
+        self.forms.add(weight, &words, &surface_words);
+
+        if let Some(statistics) = &mut self.statistics {
+            statistics.add(sentence, &words);
+        }
+    }
+
+    fn merge(self, later: Scan) -> Scan {
+        Scan {
+            counts: self.counts.merge(later.counts),
+            forms: self.forms.merge(later.forms),
+
+            statistics: match (self.statistics, later.statistics) {
+                (Some(statistics), Some(later)) => Some(statistics.merge(later)),
+                (statistics, later) => statistics.or(later),
+            },
+        }
+    }
+}
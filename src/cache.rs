@@ -0,0 +1,264 @@
+//! The container format of the `.completion-cache` and `.prediction-cache` files.
+//!
+//! Each cache file consists of:
+//! 1. The magic bytes `TXTCACHE`.
+//! 2. The format version, a little-endian `u32`, see `FORMAT_VERSION`.
+//! 3. The bincode-encoded `Header`, describing what the cache contains,
+//!    the manifest of the corpus files it was built from, and the checksum of the payload.
+//! 4. The payload, which is the actual data of the model, laid out such that it can be queried in place.
+//!    See the `mapped` module.
+//!
+//! Loading a cache maps the file into memory, and only reads the parts of the payload that are queried.
+//! The checksum is therefore only verified by the build and append commands, which read the whole file anyway.
+//! Caches are always replaced by renaming a new file, such that mapped files never change.
+//!
+//! A cache is rebuilt automatically when the corpus files have changed,
+//! unless the new files are merged into it using `append`.
+//! If files have only been removed, or none are left, it is only rebuilt by an explicit build.
+//! If it is corrupted or was built with other parameters, it is reported as invalid,
+//! and is only replaced by an explicit rebuild.
+
+use crate::error::{Error, Result};
+use crate::corpus::{CorpusConfig, Manifest, ManifestEntry};
+use crate::mapped::{Bytes, Sections};
+use crate::{completion, prediction};
+use crate::prediction::Pruning;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::fs;
+
+const MAGIC: &[u8; 8] = b"TXTCACHE";
+
+/// Increment this whenever the header or any of the payloads changes its layout.
+pub const FORMAT_VERSION: u32 = 14;
+
+/// Describes the payload of a cache file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Header {
+    pub contents: Contents,
+
+    /// The corpus files that were analyzed.
+    pub manifest: Manifest,
+
+    /// The FNV-1a hash of the encoded payload.
+    pub checksum: u64,
+}
+
+/// What kind of model the cache contains, and the parameters it was built with.
+/// The tokenizer is identified by its signature.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Contents {
+    Completion { tokenizer: String, max_edit_distance: usize },
+    Prediction { max_chain_len: usize, pruning: Pruning, tokenizer: String },
+}
+
+impl Contents {
+
+    /// The number of sections of the payload.
+    fn section_count(&self) -> usize {
+        match *self {
+            Contents::Completion { .. } => completion::SECTION_COUNT,
+            Contents::Prediction { max_chain_len, .. } => prediction::section_count(max_chain_len),
+        }
+    }
+}
+
+/// When to analyze the corpus instead of using an existing cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rebuild {
+
+    /// Rebuild missing caches and caches of a changed corpus, but report invalid caches as errors,
+    /// as well as caches whose corpus files have only been removed.
+    IfOutdated,
+
+    /// Also replace caches that are corrupted or were built with other parameters.
+    IfInvalid,
+
+    /// Rebuild even if the cache is up to date.
+    Always,
+}
+
+/// The cache file of the language, which has the language as an additional extension, such as `.prediction-cache.de`.
+/// The caches of the unnamed language use the path itself.
+pub fn language_path(path: &Path, language: &str) -> PathBuf {
+    if language.is_empty() { return path.to_path_buf(); }
+
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(language);
+    path.with_file_name(name)
+}
+
+/// Map the cache file if it exists, matches the expected contents, and was built from the current corpus.
+/// Returns `None` if the cache needs to be rebuilt, along with the current manifest of the corpus.
+pub fn load(path: &Path, contents: &Contents, corpus: &CorpusConfig, rebuild: Rebuild) -> Result<(Option<Sections>, Manifest)> {
+    let bytes = match Bytes::map(path) {
+        Ok(_) if rebuild == Rebuild::Always => return Ok((None, Manifest::scan(corpus, None)?)),
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok((None, Manifest::scan(corpus, None)?)),
+        Err(error) => return Err(Error::Io(path.to_path_buf(), error)),
+    };
+
+    let invalid = |reason: String, manifest: Manifest| {
+        if rebuild == Rebuild::IfInvalid {
+            println!("{}: replacing invalid cache: {}", path.display(), reason);
+            Ok((None, manifest))
+        }
+        else {
+            Err(Error::InvalidCache(path.to_path_buf(), reason))
+        }
+    };
+
+    let (header, payload) = match decode_header(bytes.as_slice(), contents) {
+        Ok(decoded) => decoded,
+        Err(reason) => return invalid(reason, Manifest::scan(corpus, None)?),
+    };
+
+    let manifest = Manifest::scan(corpus, Some(&header.manifest))?;
+    let changes = header.manifest.changes(&manifest);
+
+    // a corpus that only lost files, possibly all of them, is more likely misconfigured than intentionally shrunk
+    let only_removed = changes.added.is_empty() && changes.edited.is_empty();
+
+    if !changes.is_empty() && only_removed && rebuild == Rebuild::IfOutdated {
+        return Err(Error::InvalidCache(path.to_path_buf(), format!("corpus files have been removed since the cache was built ({})", changes)));
+    }
+
+    if !changes.is_empty() {
+        println!("{}: corpus has changed since the cache was built ({}), rebuilding", path.display(), changes);
+        return Ok((None, manifest));
+    }
+
+    // servers only check the structure, such that they do not read the whole file on startup
+    let sections = match rebuild {
+        Rebuild::IfOutdated => Sections::read(bytes.skip(payload), contents.section_count()),
+
+        _ => verify_checksum(&header, &bytes.as_slice()[payload ..])
+            .and_then(|_| Sections::read(bytes.skip(payload), contents.section_count())),
+    };
+
+    match sections {
+        Ok(sections) => Ok((Some(sections), manifest)),
+        Err(reason) => invalid(reason, manifest),
+    }
+}
+
+/// Merge the corpus files of the directory, which are not yet part of the cache, into the cache.
+/// The directory must be part of the corpus. Fails if the cache does not exist or is invalid.
+/// The closure receives the existing payload and the new files, and returns the merged payload.
+pub fn append(
+    path: &Path, contents: &Contents, corpus: &CorpusConfig, directory: &Path,
+    merge: impl FnOnce(&Sections, &[ManifestEntry]) -> Result<Vec<u8>>
+) -> Result<()> {
+    let bytes = Bytes::map(path).map_err(|error| Error::Io(path.to_path_buf(), error))?;
+    let invalid = |reason: String| Error::InvalidCache(path.to_path_buf(), reason);
+
+    let (header, payload) = decode_header(bytes.as_slice(), contents).map_err(invalid)?;
+    verify_checksum(&header, &bytes.as_slice()[payload ..]).map_err(invalid)?;
+    let payload = Sections::read(bytes.skip(payload), contents.section_count()).map_err(invalid)?;
+
+    let directory = fs::canonicalize(directory).map_err(|error| Error::Io(directory.to_path_buf(), error))?;
+    let known: HashSet<&Path> = header.manifest.files.iter().map(|entry| entry.path.as_path()).collect();
+
+    let new_files: Vec<ManifestEntry> = Manifest::scan(corpus, Some(&header.manifest))?.files.into_iter()
+        .filter(|entry| !known.contains(entry.path.as_path()))
+        .filter(|entry| fs::canonicalize(&entry.path).map(|path| path.starts_with(&directory)).unwrap_or(false))
+        .collect();
+
+    if new_files.is_empty() {
+        println!("{}: no corpus files in {} that are not yet part of the cache", path.display(), directory.display());
+        return Ok(());
+    }
+
+    println!("{}: appending {} new corpus files", path.display(), new_files.len());
+    let merged = merge(&payload, &new_files)?;
+    drop(payload);
+
+    let mut manifest = header.manifest;
+    manifest.files.extend(new_files);
+    store(path, header.contents, manifest, &merged)
+}
+
+/// Check the magic bytes, the version and the contents, and return the header and the offset of the payload.
+fn decode_header(bytes: &[u8], contents: &Contents) -> std::result::Result<(Header, usize), String> {
+    if bytes.len() < MAGIC.len() + 4 || &bytes[.. MAGIC.len()] != MAGIC {
+        return Err("not a cache file".to_string());
+    }
+
+    let mut version = [0_u8; 4];
+    version.copy_from_slice(&bytes[MAGIC.len() .. MAGIC.len() + 4]);
+    let version = u32::from_le_bytes(version);
+
+    if version != FORMAT_VERSION {
+        return Err(format!("format version {} is not the supported version {}", version, FORMAT_VERSION));
+    }
+
+    let mut payload = &bytes[MAGIC.len() + 4 ..];
+    let header: Header = bincode::deserialize_from(&mut payload)
+        .map_err(|error| format!("unreadable header: {}", error))?;
+
+    if &header.contents != contents {
+        return Err(format!("contains {:?}, but {:?} was requested", header.contents, contents));
+    }
+
+    Ok((header, bytes.len() - payload.len()))
+}
+
+fn verify_checksum(header: &Header, payload: &[u8]) -> std::result::Result<(), String> {
+    if checksum(payload) == header.checksum { Ok(()) }
+    else { Err("checksum mismatch, the file is corrupted".to_string()) }
+}
+
+/// Write the payload to the cache file, replacing any existing file only after all was written.
+pub fn store(path: &Path, contents: Contents, manifest: Manifest, payload: &[u8]) -> Result<()> {
+    let serialization_error = |error| Error::Serialization(path.to_path_buf(), error);
+    let header = Header { contents, manifest, checksum: checksum(payload) };
+
+    let mut bytes = Vec::with_capacity(payload.len() + 128);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, &header).map_err(serialization_error)?;
+    bytes.extend_from_slice(payload);
+
+    // the caches of different languages only differ in their last extension, which is kept,
+    // and concurrent builds of the same cache each write their own file
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(format!(".incomplete-{}", std::process::id()));
+    let temporary = PathBuf::from(temporary);
+
+    if let Err(error) = fs::write(&temporary, bytes) {
+        let _ = fs::remove_file(&temporary);
+        return Err(Error::Io(temporary, error));
+    }
+
+    fs::rename(&temporary, path).map_err(|error| {
+        let _ = fs::remove_file(&temporary);
+        Error::Io(path.to_path_buf(), error)
+    })
+}
+
+/// The 64-bit FNV-1a hash of the bytes.
+pub fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv64::default();
+    std::hash::Hasher::write(&mut hasher, bytes);
+    std::hash::Hasher::finish(&hasher)
+}
+
+/// A hasher which, unlike the default hasher of the standard library,
+/// produces the same hashes on every platform and in every version.
+pub struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self { Fnv64(0xcbf2_9ce4_8422_2325) }
+}
+
+impl std::hash::Hasher for Fnv64 {
+    fn finish(&self) -> u64 { self.0 }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
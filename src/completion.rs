@@ -1,53 +1,555 @@
-use crate::corpus;
+use crate::corpus::{self, CorpusConfig, Manifest, ManifestEntry};
+use crate::tokenizer::Tokenizer;
+use crate::error::Result;
+use crate::cache::{self, Contents, Rebuild};
+use crate::language;
+use crate::correction::{self, char_vec};
 use std::path::Path;
-use std::fs::File;
-use std::iter::FromIterator;
-
-/// Return a lambda that returns a list of completions based on a word fragment.
-pub fn build() -> impl (Fn(&str) -> Vec<String>) {
-    pub use patricia_tree::PatriciaSet;
-    use patricia_tree::PatriciaMap;
-
-    let path = Path::new(".completion-cache");
-    let map = {
-        println!("attempting to load completion cache...");
-        let cache_result: Option<Vec<(Vec<u8>, usize)>> = File::open(path).ok().and_then(|file| bincode::deserialize_from(file).ok());
-
-        if let Some(result) = cache_result {
-            println!("... loaded cache");
-            PatriciaMap::from_iter(result.into_iter())
-        }
-        else {
-            println!("... invalid, computing new prediction cache");
-            let mut map = PatriciaMap::new();
-
-            for word in corpus::words() {
-                let count = map.get(&word).unwrap_or(&0) + 1;
-                map.insert(&word, count);
+use crate::mapped::{Bytes, Sections, Writer};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+/// A word that starts with the requested fragment, along with how common it is in the corpus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub word: String,
+    pub count: usize,
+
+    /// The sum of the counts of all words that start with the fragment.
+    pub prefix_count: usize,
+}
+
+impl Completion {
+
+    /// The relative frequency of this word among all words starting with the fragment.
+    pub fn probability(&self) -> f64 {
+        self.count as f64 / self.prefix_count.max(1) as f64
+    }
+}
+
+/// A word whose beginning is similar to a typed fragment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzyCompletion<'w> {
+    pub word: &'w str,
+    pub count: usize,
+
+    /// The beginning of the word that is the fewest edits away from the fragment.
+    pub prefix: &'w str,
+    pub distance: usize,
+}
+
+/// The number of occurrences of each word, sorted by word, for looking up all words that start with a fragment.
+/// Consists of the sections `WORD_OFFSETS`, `WORD_BYTES`, `WORD_COUNTS`, `FORM_OFFSETS`, `FORM_BYTES`,
+/// `NGRAM_OFFSETS`, `NGRAM_BYTES`, `NGRAM_COUNTS`, `DELETION_HASHES`, `DELETION_WORDS` and `ALPHABET`, which are read in place.
+pub struct Words {
+    sections: Sections,
+}
+
+const WORD_OFFSETS: usize = 0;
+const WORD_BYTES: usize = 1;
+const WORD_COUNTS: usize = 2;
+const FORM_OFFSETS: usize = 3; // the most common written form of each word, empty if it is the word itself
+const FORM_BYTES: usize = 4;
+const NGRAM_OFFSETS: usize = 5; // the most common character n-grams of the words, for identifying the language
+const NGRAM_BYTES: usize = 6;
+const NGRAM_COUNTS: usize = 7;
+const DELETION_HASHES: usize = 8; // the sorted hashes of the deletions of each word, for finding similar words
+const DELETION_WORDS: usize = 9; // the index of the word of each deletion hash
+const ALPHABET: usize = 10; // the `u32` code points of the characters of the words, most common first
+pub const SECTION_COUNT: usize = 11;
+
+/// The minimum share of all characters of the words that a character needs for being part of the alphabet.
+const ALPHABET_MIN_SHARE: f64 = 0.0001;
+
+/// Words that occurred at most this often are never suggested, as they might be typos themselves.
+pub const MIN_COUNT: usize = 3;
+
+/// Counts how the words are written, except for the first word of each sentence, which is capitalized anyway.
+pub struct Forms {
+
+    /// The written forms that differ from the counted word.
+    forms: HashMap<String, HashMap<String, usize>>,
+
+    /// How often each word started a sentence.
+    initial: HashMap<String, usize>,
+}
+
+impl Forms {
+    pub fn new() -> Self {
+        Forms { forms: HashMap::new(), initial: HashMap::new() }
+    }
+
+    /// Count the written forms of the words of a sentence.
+    pub fn add(&mut self, weight: usize, words: &[String], surface_words: &[String]) {
+        for (index, (word, surface_word)) in words.iter().zip(surface_words).enumerate() {
+            if index == 0 {
+                *self.initial.entry(word.clone()).or_insert(0) += weight;
+            }
+            else if surface_word != word {
+                let forms = self.forms.entry(word.clone()).or_default();
+                *forms.entry(surface_word.clone()).or_insert(0) += weight;
+            }
+        }
+    }
+
+    pub fn merge(mut self, later: Forms) -> Forms {
+        for (word, forms) in later.forms {
+            let existing = self.forms.entry(word).or_default();
+            for (form, count) in forms { *existing.entry(form).or_insert(0) += count; }
+        }
+
+        for (word, count) in later.initial {
+            *self.initial.entry(word).or_insert(0) += count;
+        }
+
+        self
+    }
+
+    /// The written form of the word, which occurred `count` times, if it is more common than the word itself.
+    pub fn most_common(&self, word: &str, count: usize) -> Option<&str> {
+        let forms = self.forms.get(word)?;
+        let initial = self.initial.get(word).cloned().unwrap_or(0);
+        let unchanged = count.saturating_sub(initial + forms.values().sum::<usize>());
+
+        forms.iter()
+            .filter(|&(_, &count)| count > unchanged)
+            .max_by(|(form, count), (other, other_count)| count.cmp(other_count).then(other.cmp(form)))
+            .map(|(form, _)| form.as_str())
+    }
+}
+
+impl Words {
+
+    /// Lay out the counted words like a cache file, indexing the deletions of up to `max_edit_distance` characters.
+    pub fn new<'w>(words: impl IntoIterator<Item = (&'w str, usize)>, forms: &'w Forms, max_edit_distance: usize) -> Self {
+        let words = words.into_iter().map(move |(word, count)| (word, count, forms.most_common(word, count)));
+        let sections = Sections::read(Bytes::from(payload(words, max_edit_distance)), SECTION_COUNT).expect("invalid completion payload");
+        Words { sections }
+    }
+
+    /// The suggested words that are at most `max_distance` edits away from the typed word, along with their counts and distances.
+    /// Candidates share a deletion with the typed word, as in the SymSpell algorithm,
+    /// so the distance must not be greater than the distance that the index was built for.
+    pub fn similar(&self, typed: &str, max_distance: usize) -> Vec<(&str, usize, usize)> {
+        let strings = self.sections.strings(WORD_OFFSETS, WORD_BYTES);
+        let counts = self.sections.array::<u64>(WORD_COUNTS);
+        let hashes = self.sections.array::<u64>(DELETION_HASHES);
+        let indices = self.sections.array::<u32>(DELETION_WORDS);
+
+        let typed = char_vec(typed);
+        let mut candidates: HashSet<usize> = HashSet::new();
+
+        for deletion in correction::deletions(&typed, max_distance) {
+            let hash = cache::checksum(deletion.as_bytes());
+            let first = hashes.partition_point(|other| other < hash);
+
+            candidates.extend((first .. hashes.len())
+                .take_while(|&index| hashes.get(index) == hash)
+                .map(|index| indices.get(index) as usize));
+        }
+
+        // different deletions might have the same hash, and sharing a deletion does not guarantee a small distance
+        candidates.into_iter()
+            .map(|index| (strings.get(index), counts.get(index) as usize))
+            .map(|(word, count)| (word, count, correction::distance(&typed, &char_vec(word))))
+            .filter(|&(_, _, distance)| distance <= max_distance)
+            .collect()
+    }
+
+    /// The most common character n-grams of the words, sorted by n-gram, along with their counts.
+    pub fn ngrams(&self) -> impl Iterator<Item = (&str, usize)> {
+        let counts = self.sections.array::<u64>(NGRAM_COUNTS);
+        self.sections.strings(NGRAM_OFFSETS, NGRAM_BYTES).iter().zip(counts.iter()).map(|(ngram, count)| (ngram, count as usize))
+    }
+
+    /// The suggested words that start with up to `max_distance` edits of the fragment, closest and most common first.
+    /// Walks the sorted words like a trie, running a Levenshtein automaton that also accepts transpositions:
+    /// each level of the trie adds a row of edit distances, which is shared by all words with the same beginning,
+    /// and all words with a beginning that cannot come close enough to the fragment anymore are skipped at once.
+    pub fn with_fuzzy_prefix(&self, fragment: &str, max_distance: usize) -> Vec<FuzzyCompletion<'_>> {
+        let strings = self.sections.strings(WORD_OFFSETS, WORD_BYTES);
+        let counts = self.sections.array::<u64>(WORD_COUNTS);
+
+        let fragment = char_vec(fragment);
+        let last = fragment.len();
+
+        // the path of the current word through the trie, and the distances after each of its characters
+        let mut path: Vec<(char, usize)> = Vec::new(); // each character along with its byte offset in the word
+        let mut rows: Vec<Vec<usize>> = vec![ (0 ..= last).collect() ];
+
+        // the smallest distance between the fragment and any beginning of the path, and the length of that beginning
+        let mut best: Vec<(usize, usize)> = vec![ (last, 0) ];
+
+        let mut completions = Vec::new();
+        let mut index = 0;
+
+        while index < strings.len() {
+            let word = strings.get(index);
+            let chars: Vec<(usize, char)> = word.char_indices().collect();
+
+            let common = path.iter().zip(&chars).take_while(|((char, _), (_, other))| char == other).count();
+            path.truncate(common);
+            rows.truncate(common + 1);
+            best.truncate(common + 1);
+
+            let mut hopeless = false;
+
+            for &(offset, char) in &chars[common ..] {
+                let previous = &rows[rows.len() - 1];
+                let mut row = vec![ previous[0] + 1 ];
+
+                for position in 1 ..= last {
+                    let substitution = if fragment[position - 1] == char { 0 } else { 1 };
+                    let mut distance = (previous[position - 1] + substitution).min(previous[position] + 1).min(row[position - 1] + 1);
+
+                    let transposed = position > 1 && path.last().map(|&(before, _)| before) == Some(fragment[position - 1])
+                        && fragment[position - 2] == char;
+
+                    if transposed { distance = distance.min(rows[rows.len() - 2][position - 2] + 1); }
+                    row.push(distance);
+                }
+
+                let (best_distance, best_len) = best[best.len() - 1];
+                let len = path.len() + 1;
+
+                // of equally distant beginnings, the one with the length of the fragment is the most plausible
+                let closer = row[last] < best_distance || (row[last] == best_distance
+                    && (len as isize - last as isize).abs() < (best_len as isize - last as isize).abs());
+
+                best.push(if closer { (row[last], len) } else { (best_distance, best_len) });
+                hopeless = row.iter().all(|&distance| distance > max_distance);
+
+                path.push((char, offset));
+                rows.push(row);
+
+                if hopeless { break; }
+            }
+
+            let (distance, len) = best[best.len() - 1];
+
+            // no longer beginning comes closer, so all words with this beginning share the same distance
+            let beginning = &word[.. path.last().map(|&(char, offset)| offset + char.len_utf8()).unwrap_or(0)];
+            let end = if hopeless { strings.partition_point(|other| other < beginning || other.starts_with(beginning)) } else { index + 1 };
+
+            if distance <= max_distance {
+                let prefix_bytes = path.get(len.wrapping_sub(1)).map(|&(char, offset)| offset + char.len_utf8()).unwrap_or(0);
+
+                completions.extend((index .. end)
+                    .map(|index| (strings.get(index), counts.get(index) as usize))
+                    .filter(|&(_, count)| count > MIN_COUNT)
+                    .map(|(word, count)| FuzzyCompletion { word, count, prefix: &word[.. prefix_bytes], distance }));
             }
 
-            bincode::serialize_into(
-                File::create(path).unwrap(),
-                &map.clone().into_iter().collect::<Vec<(Vec<u8>, usize)>>()
-            ).unwrap();
+            index = end;
+        }
+
+        completions.sort_by(|a, b| a.distance.cmp(&b.distance).then(b.count.cmp(&a.count)).then(a.word.cmp(b.word)));
+        completions
+    }
+
+    /// The characters of the language, most common first, which leaves out the rare characters of foreign words.
+    pub fn alphabet(&self) -> Vec<char> {
+        self.sections.array::<u32>(ALPHABET).iter().filter_map(std::char::from_u32).collect()
+    }
+
+    /// The most common written form of the word, or the word itself if it is unknown.
+    pub fn form<'w>(&'w self, word: &'w str) -> &'w str {
+        let strings = self.sections.strings(WORD_OFFSETS, WORD_BYTES);
+        let index = strings.partition_point(|other| other < word);
 
-            map
+        if index < strings.len() && strings.get(index) == word {
+            let form = self.sections.strings(FORM_OFFSETS, FORM_BYTES).get(index);
+            if !form.is_empty() { return form; }
         }
-    };
 
-    move |previous_word|{
-        let mut completions: Vec<(String, usize)> = map
-            .iter_prefix(previous_word.as_bytes())
-            .map(|(word, &count)| (String::from_utf8(word).unwrap(), count)).collect();
+        word
+    }
+
+    /// All words that start with the fragment, sorted by their bytes, along with their counts.
+    pub fn with_prefix<'w>(&'w self, prefix: &'w str) -> impl Iterator<Item = (&'w str, usize)> {
+        let strings = self.sections.strings(WORD_OFFSETS, WORD_BYTES);
+        let counts = self.sections.array::<u64>(WORD_COUNTS);
+
+        let first = strings.partition_point(|word| word < prefix);
+
+        (first .. strings.len())
+            .map(move |index| (strings.get(index), counts.get(index) as usize))
+            .take_while(move |(word, _)| word.starts_with(prefix))
+    }
+}
+
+/// Map the word counts of the cache file, unless it is outdated.
+pub fn load(
+    corpus: &CorpusConfig, cache: &Path, tokenizer: &dyn Tokenizer, max_edit_distance: usize, rebuild: Rebuild
+) -> Result<(Option<Words>, Manifest)> {
+    let (sections, manifest) = cache::load(cache, &contents(tokenizer, max_edit_distance), corpus, rebuild)?;
+    Ok((sections.map(|sections| Words { sections }), manifest))
+}
+
+/// Write the word counts to the cache file.
+pub fn store(cache: &Path, manifest: Manifest, words: &Words, tokenizer: &dyn Tokenizer, max_edit_distance: usize) -> Result<()> {
+    cache::store(cache, contents(tokenizer, max_edit_distance), manifest, words.sections.payload())
+}
+
+/// Return a lambda that returns a list of completions based on a word fragment, most common first.
+pub fn completer(words: Arc<Words>) -> impl Fn(&str) -> Vec<Completion> {
+    move |previous_word: &str|{
+        let mut completions: Vec<(&str, usize)> = words.with_prefix(previous_word).collect();
 
         // sort the completions by number of occurrences in the corpus (best at last)
         completions.sort_by_key(|(_, count)| *count);
+        let prefix_count = completions.iter().map(|(_, count)| count).sum();
 
         completions.into_iter().rev()
-            .filter(|(_, count)| *count > 3)
-            .map(|(word, _)| word)
+            .filter(|(_, count)| *count > MIN_COUNT)
+            .map(|(word, count)| Completion { word: word.to_string(), count, prefix_count })
             .collect()
     }
 }
 
+/// Return a lambda that returns the most common written form of a word.
+pub fn former(words: Arc<Words>) -> impl Fn(&str) -> String {
+    move |word: &str| words.form(word).to_string()
+}
+
+/// Merge the new files of the directory into the existing completion cache.
+/// The written forms of the known words are kept, as their previous counts are not stored.
+pub fn append(corpus: &CorpusConfig, cache: &Path, tokenizer: &dyn Tokenizer, max_edit_distance: usize, directory: &Path) -> Result<()> {
+    cache::append(cache, &contents(tokenizer, max_edit_distance), corpus, directory, |sections: &Sections, files: &[ManifestEntry]| {
+        let (new_words, new_forms) = corpus::fold_sentences_of(
+            files, corpus.on_error, || (HashMap::new(), Forms::new()),
+            |(words, forms), weight, sentence| add_sentence(tokenizer, words, forms, weight, sentence),
+            |(words, forms), (later_words, later_forms)| (merge_counts(words, later_words), forms.merge(later_forms))
+        )?;
+
+        let new_words = new_words.iter().map(|(word, &count)| (word.as_str(), count, new_forms.most_common(word, count)));
+        Ok(payload(entries(sections).chain(new_words), max_edit_distance))
+    })
+}
+
+fn entries(sections: &Sections) -> impl Iterator<Item = (&str, usize, Option<&str>)> {
+    let counts = sections.array::<u64>(WORD_COUNTS);
+    let forms = sections.strings(FORM_OFFSETS, FORM_BYTES).iter().map(|form| Some(form).filter(|form| !form.is_empty()));
+
+    sections.strings(WORD_OFFSETS, WORD_BYTES).iter().zip(counts.iter()).zip(forms)
+        .map(|((word, count), form)| (word, count as usize, form))
+}
+
+/// Sort the words, adding up the counts of duplicates, and write the sections.
+/// Of duplicates, the first known written form is kept.
+fn payload<'w>(words: impl IntoIterator<Item = (&'w str, usize, Option<&'w str>)>, max_edit_distance: usize) -> Vec<u8> {
+    let mut sorted: BTreeMap<&str, (usize, Option<&str>)> = BTreeMap::new();
+
+    for (word, count, form) in words {
+        let entry = sorted.entry(word).or_insert((0, None));
+        entry.0 += count;
+        entry.1 = entry.1.or(form);
+    }
+
+    let mut writer = Writer::default();
+    writer.strings(sorted.keys().cloned());
+    writer.array(sorted.values().map(|&(count, _)| count as u64));
+    writer.strings(sorted.values().map(|&(_, form)| form.unwrap_or("")));
+
+    let ngrams = language::most_common(sorted.iter().map(|(word, &(count, _))| (*word, count)));
+    writer.strings(ngrams.iter().map(|(ngram, _)| ngram.as_str()));
+    writer.array(ngrams.iter().map(|&(_, count)| count as u64));
+
+    let deletions = deletions(sorted.iter().map(|(word, &(count, _))| (*word, count)), max_edit_distance);
+    writer.array(deletions.iter().map(|&(hash, _)| hash));
+    writer.array(deletions.iter().map(|&(_, index)| index));
+    writer.array(alphabet(sorted.iter().map(|(word, &(count, _))| (*word, count))).into_iter().map(|char| char as u32));
+    writer.finish()
+}
+
+/// The characters that make up at least `ALPHABET_MIN_SHARE` of the characters of all words, most common first.
+fn alphabet<'w>(words: impl Iterator<Item = (&'w str, usize)>) -> Vec<char> {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+
+    for (word, count) in words {
+        for char in word.chars() { *counts.entry(char).or_insert(0) += count; }
+    }
+
+    let total = counts.values().sum::<usize>() as f64;
+    let mut alphabet: Vec<(char, usize)> = counts.into_iter()
+        .filter(|&(_, count)| count as f64 >= total * ALPHABET_MIN_SHARE)
+        .collect();
+
+    alphabet.sort_by(|(char, count), (other, other_count)| other_count.cmp(count).then(char.cmp(other)));
+    alphabet.into_iter().map(|(char, _)| char).collect()
+}
+
+/// The hashes of the deletions of the words that are suggested, along with the index of their word, sorted by hash.
+fn deletions<'w>(words: impl Iterator<Item = (&'w str, usize)>, max_edit_distance: usize) -> Vec<(u64, u32)> {
+    let mut deletions: Vec<(u64, u32)> = words.enumerate()
+        .filter(|&(_, (_, count))| count > MIN_COUNT)
+        .flat_map(|(index, (word, _))| correction::deletions(&char_vec(word), max_edit_distance).into_iter()
+            .map(move |deletion| (cache::checksum(deletion.as_bytes()), index as u32)))
+        .collect();
+
+    deletions.sort_unstable();
+    deletions
+}
+
+fn contents(tokenizer: &dyn Tokenizer, max_edit_distance: usize) -> Contents {
+    Contents::Completion { tokenizer: tokenizer.signature(), max_edit_distance }
+}
+
+fn add_sentence(tokenizer: &dyn Tokenizer, words: &mut HashMap<String, usize>, forms: &mut Forms, weight: usize, sentence: String) {
+    let surface_words = tokenizer.surface_words(&sentence);
+    let sentence: Vec<String> = surface_words.iter().map(|word| tokenizer.fold_case(word)).collect();
+    forms.add(weight, &sentence, &surface_words);
+
+    for word in sentence {
+        *words.entry(word).or_insert(0) += weight;
+    }
+}
+
+/// Merge the word counts of two workers, moving the smaller map into the larger one.
+fn merge_counts(first: HashMap<String, usize>, second: HashMap<String, usize>) -> HashMap<String, usize> {
+    let (mut larger, smaller) = if first.len() >= second.len() { (first, second) } else { (second, first) };
+
+    for (word, count) in smaller {
+        *larger.entry(word).or_insert(0) += count;
+    }
+
+    larger
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::ErrorPolicy;
+    use crate::tokenizer::UnicodeTokenizer;
 
+    /// Common words, some of which are longer than the indexed prefix of their deletions, and a rare word.
+    fn words() -> Words {
+        let common = [ "the", "then", "than", "form", "from", "forms", "information", "informative", "international" ];
+        let words = common.iter().map(|&word| (word, 10)).chain(std::iter::once(("thee", MIN_COUNT)));
+        Words::new(words, &Forms::new(), 2)
+    }
+
+    /// The similar words and their distances, sorted by word.
+    fn similar(words: &Words, typed: &str, max_distance: usize) -> Vec<(String, usize)> {
+        let mut similar: Vec<(String, usize)> = words.similar(typed, max_distance).into_iter()
+            .map(|(word, _, distance)| (word.to_string(), distance)).collect();
+
+        similar.sort();
+        similar
+    }
+
+    fn expected(pairs: &[(&str, usize)]) -> Vec<(String, usize)> {
+        pairs.iter().map(|&(word, distance)| (word.to_string(), distance)).collect()
+    }
+
+    #[test]
+    fn finds_the_word_itself_at_distance_zero() {
+        let words = words();
+        assert_eq!(similar(&words, "from", 0), expected(&[ ("from", 0) ]));
+        assert_eq!(similar(&words, "fro", 0), expected(&[]));
+    }
+
+    #[test]
+    fn finds_words_within_one_and_two_edits() {
+        let words = words();
+        assert_eq!(similar(&words, "thn", 1), expected(&[ ("than", 1), ("the", 1), ("then", 1) ]));
+        assert_eq!(similar(&words, "tan", 2), expected(&[ ("than", 1), ("the", 2), ("then", 2) ]));
+        assert_eq!(similar(&words, "frms", 2), expected(&[ ("form", 2), ("forms", 1), ("from", 2) ]));
+    }
+
+    #[test]
+    fn counts_a_transposition_as_one_edit() {
+        let words = words();
+        assert_eq!(similar(&words, "fomr", 1), expected(&[ ("form", 1) ]));
+        assert_eq!(similar(&words, "hte", 1), expected(&[ ("the", 1) ]));
+    }
+
+    #[test]
+    fn finds_words_longer_than_the_indexed_prefix() {
+        let words = words();
+
+        // within, at the end of, and after the indexed prefix of seven characters
+        assert_eq!(similar(&words, "infromation", 1), expected(&[ ("information", 1) ]));
+        assert_eq!(similar(&words, "informtaion", 1), expected(&[ ("information", 1) ]));
+        assert_eq!(similar(&words, "informatoin", 1), expected(&[ ("information", 1) ]));
+        assert_eq!(similar(&words, "informatio", 2), expected(&[ ("information", 1), ("informative", 2) ]));
+    }
+
+    #[test]
+    fn finds_all_words_within_the_distance() {
+        let words = words();
+        let suggested: Vec<&str> = words.with_prefix("").filter(|&(_, count)| count > MIN_COUNT).map(|(word, _)| word).collect();
+
+        for typed in &[ "", "t", "th", "tha", "thenn", "fomrs", "infromatoin", "internatoinal", "xyz" ] {
+            for max_distance in 0 ..= 2 {
+                let all: Vec<(String, usize)> = suggested.iter()
+                    .map(|&word| (word.to_string(), correction::distance(&char_vec(typed), &char_vec(word))))
+                    .filter(|&(_, distance)| distance <= max_distance)
+                    .collect();
+
+                assert_eq!(similar(&words, typed, max_distance), all, "{} within {}", typed, max_distance);
+            }
+        }
+    }
+
+    #[test]
+    fn does_not_suggest_rare_words() {
+        assert!(similar(&words(), "thee", 0).is_empty());
+    }
+
+    /// The words that start with up to `max_distance` edits of the fragment, in the order of their rank.
+    fn fuzzy(words: &Words, fragment: &str, max_distance: usize) -> Vec<(String, usize)> {
+        words.with_fuzzy_prefix(fragment, max_distance).into_iter()
+            .map(|completion| (completion.word.to_string(), completion.distance)).collect()
+    }
+
+    #[test]
+    fn completes_fragments_with_an_edit_at_the_first_character() {
+        let words = words();
+        assert_eq!(fuzzy(&words, "rom", 1), expected(&[ ("from", 1) ]), "deletion");
+        assert_eq!(fuzzy(&words, "ffrom", 1), expected(&[ ("from", 1) ]), "insertion");
+        assert_eq!(fuzzy(&words, "gro", 1), expected(&[ ("from", 1) ]), "substitution");
+        assert_eq!(fuzzy(&words, "rfo", 1), expected(&[ ("form", 1), ("forms", 1), ("from", 1) ]), "transposition");
+    }
+
+    #[test]
+    fn completes_fragments_within_the_maximum_distance() {
+        let words = words();
+        assert_eq!(fuzzy(&words, "imfor", 0), expected(&[]));
+        assert_eq!(fuzzy(&words, "imfor", 1), expected(&[ ("information", 1), ("informative", 1) ]));
+        assert_eq!(fuzzy(&words, "imfor", 2), expected(&[ ("information", 1), ("informative", 1), ("form", 2), ("forms", 2) ]));
+    }
+
+    #[test]
+    fn ranks_fuzzy_completions_by_distance_and_count() {
+        let more = [ ("form", 10), ("formal", 30), ("format", 20), ("former", 20), ("from", 50) ];
+        let words = Words::new(more.iter().cloned(), &Forms::new(), 2);
+
+        // the most common word is the furthest away, and equally common words are sorted by their bytes
+        assert_eq!(fuzzy(&words, "forn", 2), expected(&[
+            ("formal", 1), ("format", 1), ("former", 1), ("form", 1), ("from", 2),
+        ]));
+    }
+
+    #[test]
+    fn finds_similar_words_after_storing_and_loading_the_cache() {
+        let directory = std::env::temp_dir().join(format!("text-analysis-completion-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let cache = directory.join("completion-cache");
+        let corpus = CorpusConfig::from_roots(Vec::new(), None, None, "", ErrorPolicy::Abort).unwrap();
+        let tokenizer = UnicodeTokenizer::default();
+
+        let words = words();
+        store(&cache, Manifest::default(), &words, &tokenizer, 2).unwrap();
+        let (loaded, _) = load(&corpus, &cache, &tokenizer, 2, Rebuild::IfOutdated).unwrap();
+        let loaded = loaded.unwrap();
+
+        for typed in &[ "thn", "fomr", "infromatoin", "informatio" ] {
+            assert_eq!(similar(&loaded, typed, 2), similar(&words, typed, 2));
+        }
+
+        assert_eq!(loaded.with_prefix("").collect::<Vec<_>>(), words.with_prefix("").collect::<Vec<_>>());
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
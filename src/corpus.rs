@@ -1,56 +1,400 @@
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use crate::error::{Error, Result};
+use crate::cache;
+use crate::sentences::split_to_sentences;
+use rayon::prelude::*;
+use std::fmt;
 
-use std::ffi::OsStr;
-use std::io::{BufReader, BufRead};
-use std::fs::File;
-
-pub fn sentences() -> impl Iterator<Item = String> {
-    // let directory = "corpora/norvig-com-big.txt"; // TODO
-    let directory = "corpora";
-    // let directory = "corpora/oanc";
-
-    let files = walkdir::WalkDir::new(directory)
-        .into_iter().filter_entry(|entry| !entry.path().file_name().unwrap().to_str().unwrap().starts_with("_"))
-        .map(Result::unwrap).filter(|entry| entry.path().extension() == Some(OsStr::new("txt"))) // ignore xml files
-        .map(walkdir::DirEntry::into_path);
-
-    let sentences = files.into_iter().flat_map(|path| {
-        let mut chars = BufReader::new(File::open(path).unwrap())
-            .lines().flat_map(|string| string.unwrap().chars().collect::<Vec<char>>().into_iter());
-
-        std::iter::from_fn(move || {
-            let mut sentence = String::with_capacity(256);
-
-            while let Some(character) = chars.next() {
-                if "!?.".contains(character) {
-                    let sentence = sentence.replace("-\n", ""); // merge words that have been split by a linebreak
-                    return Some(sentence);
-                }
-                else {
-                    sentence.push(character);
-                }
+
+/// Which text files make up the corpus, loaded from a TOML or JSON file or from the command line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CorpusConfig {
+    pub sources: Vec<Source>,
+
+    /// Whether to skip or to abort on unreadable files.
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
+}
+
+/// A directory (or single file) of text files.
+/// Every word in these files counts `weight` times.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Source {
+    pub root: PathBuf,
+
+    /// Glob patterns of the files to read, relative to the root.
+    #[serde(default = "Source::default_include")]
+    pub include: Vec<String>,
+
+    /// Glob patterns of the files and directories to skip, relative to the root.
+    #[serde(default = "Source::default_exclude")]
+    pub exclude: Vec<String>,
+
+    #[serde(default = "Source::default_weight")]
+    pub weight: usize,
+
+    /// The name of the language of the text files, such as `de`. Each language has its own models.
+    /// Sources without a language make up the unnamed language, whose caches have no suffix.
+    #[serde(default)]
+    pub language: String,
+}
+
+/// What to do when a corpus file cannot be read.
+/// Skipped files are listed in a summary after reading the corpus.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy { #[default] Skip, Abort }
+
+impl ErrorPolicy {
+
+    /// Returns the value, or remembers and ignores the error when skipping, or returns the error when aborting.
+    fn handle<T>(self, result: Result<T>, skipped: &mut Vec<Error>) -> Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(error) if self == ErrorPolicy::Skip => {
+                eprintln!("skipping {}", error);
+                skipped.push(error);
+                Ok(None)
+            },
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl CorpusConfig {
+
+    /// Load a `.toml` or `.json` file that contains a list of `[[sources]]`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|error| Error::reading(path, error))?;
+        let invalid = |message: String| Error::Config(path.to_path_buf(), message);
+
+        let config: CorpusConfig = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => serde_json::from_str(&text).map_err(|error| invalid(error.to_string()))?,
+            _ => toml::from_str(&text).map_err(|error| invalid(error.to_string()))?,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Use the same patterns and language for each of the root directories, each with a weight of one.
+    pub fn from_roots(
+        roots: Vec<PathBuf>, include: Option<Vec<String>>, exclude: Option<Vec<String>>,
+        language: &str, on_error: ErrorPolicy
+    ) -> Result<Self> {
+        let config = CorpusConfig {
+            on_error,
+            sources: roots.into_iter().map(|root| Source {
+                root,
+                include: include.clone().unwrap_or_else(Source::default_include),
+                exclude: exclude.clone().unwrap_or_else(Source::default_exclude),
+                weight: Source::default_weight(),
+                language: language.to_string(),
+            }).collect()
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The languages of the sources, in the order of their first source.
+    /// Without any sources, this is only the unnamed language.
+    pub fn languages(&self) -> Vec<String> {
+        let mut languages: Vec<String> = Vec::new();
+
+        for source in &self.sources {
+            if !languages.contains(&source.language) { languages.push(source.language.clone()); }
+        }
+
+        if languages.is_empty() { languages.push(String::new()); }
+        languages
+    }
+
+    /// Only the sources of the language.
+    pub fn language(&self, language: &str) -> CorpusConfig {
+        CorpusConfig {
+            sources: self.sources.iter().filter(|source| source.language == language).cloned().collect(),
+            on_error: self.on_error,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        for source in &self.sources {
+            source.patterns()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Source {
+    fn default_include() -> Vec<String> { vec![ "**/*.txt".to_string() ] } // ignore xml files
+    fn default_exclude() -> Vec<String> { vec![ "**/_*".to_string() ] }
+    fn default_weight() -> usize { 1 }
+
+    /// Returns the compiled include and exclude patterns.
+    fn patterns(&self) -> Result<(GlobSet, GlobSet)> {
+        let compile = |patterns: &[String]| -> Result<GlobSet> {
+            let invalid = |error: globset::Error| Error::Config(self.root.clone(), error.to_string());
+            let mut set = GlobSetBuilder::new();
+
+            for pattern in patterns {
+                set.add(Glob::new(pattern).map_err(invalid)?);
+            }
+
+            set.build().map_err(invalid)
+        };
+
+        Ok((compile(&self.include)?, compile(&self.exclude)?))
+    }
+
+    /// The path relative to the root, used for matching the patterns.
+    /// If the root is a single file, this is the file name.
+    fn relative_path<'p>(&self, path: &'p Path) -> &'p Path {
+        match path.strip_prefix(&self.root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => path.file_name().map(Path::new).unwrap_or(path),
+        }
+    }
+
+    /// Fails if the root does not exist or cannot be read, which usually means that it is mistyped.
+    fn check_root(&self) -> Result<()> {
+        let unreadable = |error: std::io::Error| Error::Io(self.root.clone(), error);
+
+        if std::fs::metadata(&self.root).map_err(unreadable)?.is_dir() { std::fs::read_dir(&self.root).map_err(unreadable)?; }
+        else { std::fs::File::open(&self.root).map_err(unreadable)?; }
+
+        Ok(())
+    }
+
+    /// All files of this source that match the patterns.
+    /// Yields an error for each directory that cannot be listed.
+    pub fn files(&self) -> impl Iterator<Item = Result<PathBuf>> + '_ {
+        let (include, exclude) = self.patterns().expect("patterns have been validated");
+        let is_excluded = move |path: &Path| exclude.is_match(self.relative_path(path));
+
+        walkdir::WalkDir::new(&self.root)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter().filter_entry(move |entry| !is_excluded(entry.path()))
+            .filter(|entry| entry.as_ref().map(|entry| entry.file_type().is_file()).unwrap_or(true))
+            .filter(move |entry| entry.as_ref().map(|entry| include.is_match(self.relative_path(entry.path()))).unwrap_or(true))
+            .map(move |entry| entry.map(walkdir::DirEntry::into_path).map_err(|error| {
+                let path = error.path().unwrap_or(&self.root).to_path_buf();
+                let message = error.to_string(); // file system loops have no io error
+
+                Error::Io(path, error.into_io_error().unwrap_or_else(|| std::io::Error::other(message)))
+            }))
+    }
+}
+
+/// The list of corpus files that a model was built from.
+/// Used to detect added, removed and edited files.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Manifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub weight: usize,
+    pub size: u64,
+
+    /// Seconds and nanoseconds since the unix epoch.
+    pub modified: (u64, u32),
+
+    /// The checksum of the file contents, see `cache::checksum`.
+    pub hash: u64,
+}
+
+/// The differences between two manifests.
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub edited: Vec<PathBuf>,
+}
+
+impl Manifest {
+
+    /// List all files of the corpus. Only hashes the contents of files which
+    /// have a different size or modification time than in the previous manifest.
+    /// Unreadable files are not listed, they are reported when actually reading the corpus.
+    /// Fails if the root of a source is missing or unreadable, instead of listing none of its files.
+    pub fn scan(config: &CorpusConfig, previous: Option<&Manifest>) -> Result<Self> {
+        let previous: HashMap<&Path, &ManifestEntry> = previous.into_iter()
+            .flat_map(|manifest| manifest.files.iter())
+            .map(|entry| (entry.path.as_path(), entry))
+            .collect();
+
+        let mut files = Vec::new();
+
+        for source in &config.sources {
+            source.check_root()?;
+
+            for path in source.files().filter_map(|path| path.ok()) {
+                let metadata = match std::fs::metadata(&path) { Ok(metadata) => metadata, Err(_) => continue };
+
+                let modified = metadata.modified().ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+
+                let modified = (modified.as_secs(), modified.subsec_nanos());
+                let size = metadata.len();
+
+                let hash = match previous.get(path.as_path()) {
+                    Some(entry) if entry.size == size && entry.modified == modified => entry.hash,
+                    _ => match std::fs::read(&path) { Ok(bytes) => cache::checksum(&bytes), Err(_) => continue },
+                };
+
+                files.push(ManifestEntry { path, weight: source.weight, size, modified, hash });
+            }
+        }
+
+        Ok(Manifest { files })
+    }
+
+    /// Which files differ in the other manifest. Files which have only been touched are not considered edited.
+    pub fn changes(&self, newer: &Manifest) -> Changes {
+        let old: HashMap<&Path, &ManifestEntry> = self.files.iter().map(|entry| (entry.path.as_path(), entry)).collect();
+        let new: HashMap<&Path, &ManifestEntry> = newer.files.iter().map(|entry| (entry.path.as_path(), entry)).collect();
+
+        let mut changes = Changes::default();
+
+        for entry in &newer.files {
+            match old.get(entry.path.as_path()) {
+                None => changes.added.push(entry.path.clone()),
+                Some(old) if old.hash != entry.hash || old.weight != entry.weight => changes.edited.push(entry.path.clone()),
+                Some(_) => {},
+            }
+        }
+
+        for entry in &self.files {
+            if !new.contains_key(entry.path.as_path()) {
+                changes.removed.push(entry.path.clone());
+            }
+        }
+
+        changes
+    }
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.edited.is_empty()
+    }
+}
+
+impl fmt::Display for Changes {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{} added, {} removed, {} edited files", self.added.len(), self.removed.len(), self.edited.len())
+    }
+}
+
+/// Call the closure for all sentences of all files in the corpus, along with the weight of their source.
+/// Depending on the error policy, unreadable files are skipped and listed at the end, or abort the whole process.
+pub fn for_each_sentence(config: &CorpusConfig, consume: impl FnMut(usize, String)) -> Result<()> {
+    read_files(corpus_files(config), config.on_error, consume)
+}
+
+/// Like `for_each_sentence`, but reads the files in parallel. Each worker adds the sentences of its files,
+/// along with the weight of their source, to its own partial result, which starts out `empty`.
+/// The partial results of neighbouring files are merged, such that `merge` always receives the earlier files first.
+pub fn fold_sentences<T: Send>(
+    config: &CorpusConfig, empty: impl Fn() -> T + Sync + Send,
+    add: impl Fn(&mut T, usize, String) + Sync + Send, merge: impl Fn(T, T) -> T + Sync + Send,
+) -> Result<T> {
+    let mut skipped = Vec::new();
+    let mut files = Vec::new();
+
+    for file in corpus_files(config) {
+        if let Some(file) = config.on_error.handle(file, &mut skipped)? {
+            files.push(file);
+        }
+    }
+
+    fold_files(files, skipped, config.on_error, empty, add, merge)
+}
+
+/// Like `fold_sentences`, but only reads the listed files.
+pub fn fold_sentences_of<T: Send>(
+    files: &[ManifestEntry], on_error: ErrorPolicy, empty: impl Fn() -> T + Sync + Send,
+    add: impl Fn(&mut T, usize, String) + Sync + Send, merge: impl Fn(T, T) -> T + Sync + Send,
+) -> Result<T> {
+    let files = files.iter().map(|entry| (entry.path.clone(), entry.weight)).collect();
+    fold_files(files, Vec::new(), on_error, empty, add, merge)
+}
+
+/// All files of all sources, along with the weight of their source.
+fn corpus_files(config: &CorpusConfig) -> impl Iterator<Item = Result<(PathBuf, usize)>> + '_ {
+    config.sources.iter().flat_map(|source| {
+        source.files().map(move |path| path.map(|path| (path, source.weight)))
+    })
+}
+
+fn read_files(files: impl Iterator<Item = Result<(PathBuf, usize)>>, on_error: ErrorPolicy, mut consume: impl FnMut(usize, String)) -> Result<()> {
+    let mut skipped = Vec::new();
+    let mut file_count = 0;
+
+    for file in files {
+        let (path, weight) = match on_error.handle(file, &mut skipped)? {
+            Some(file) => file, None => continue
+        };
+
+        let text = match on_error.handle(read_text(&path), &mut skipped)? {
+            Some(text) => text, None => continue
+        };
+
+        file_count += 1;
+
+        for sentence in split_to_sentences(&text) {
+            consume(weight, sentence);
+        }
+    }
+
+    report(file_count, &skipped);
+    Ok(())
+}
+
+fn fold_files<T: Send>(
+    files: Vec<(PathBuf, usize)>, mut skipped: Vec<Error>, on_error: ErrorPolicy, empty: impl Fn() -> T + Sync + Send,
+    add: impl Fn(&mut T, usize, String) + Sync + Send, merge: impl Fn(T, T) -> T + Sync + Send,
+) -> Result<T> {
+    let (result, file_count, mut worker_skipped) = files.into_par_iter()
+        .try_fold(|| (empty(), 0, Vec::new()), |(mut result, file_count, mut skipped), (path, weight)| {
+            let text = match on_error.handle(read_text(&path), &mut skipped)? {
+                Some(text) => text, None => return Ok((result, file_count, skipped))
+            };
+
+            for sentence in split_to_sentences(&text) {
+                add(&mut result, weight, sentence);
             }
 
-            // do not return incomplete sentences at file end
-            return None;
+            Ok((result, file_count + 1, skipped))
         })
-    });
+        .try_reduce(|| (empty(), 0, Vec::new()), |(earlier, earlier_count, mut skipped), (later, later_count, later_skipped)| {
+            skipped.extend(later_skipped);
+            Ok((merge(earlier, later), earlier_count + later_count, skipped))
+        })?;
 
-    sentences.filter_map(|sentence| if !sentence.is_empty() { Some(sentence) } else { None })
+    skipped.append(&mut worker_skipped);
+    report(file_count, &skipped);
+    Ok(result)
 }
 
-/// May return an empty string
-pub fn split_to_words(sentence: &str) -> Vec<String> {
-    sentence.split_whitespace()
-        .map(|word|
-            word.chars()
-                .flat_map(|c| c.to_lowercase())
-                .filter(|&c| c.is_alphabetic() || c == '\'')
-                .collect::<String>()
-        )
-        .filter(|w| !w.is_empty() && w != "\'")
-        .collect()
+/// Read the whole file first, such that an invalid file does not contribute any sentences.
+fn read_text(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|error| Error::reading(path, error))
 }
 
-pub fn words() -> impl Iterator<Item = String> {
-    self::sentences().flat_map(|string| split_to_words(&string))
-}
\ No newline at end of file
+fn report(file_count: usize, skipped: &[Error]) {
+    println!("read {} corpus files", file_count);
+
+    if !skipped.is_empty() {
+        println!("skipped {} unreadable corpus files:", skipped.len());
+        for error in skipped { println!("\t{}", error); }
+    }
+}
@@ -1,64 +1,383 @@
-
-
-// macro_rules! concat_iter {
-//     ($iter1: expr) => { $iter1 };
-//
-//     ($iter1: expr, $iter2: expr) => {{
-//         let (mut i1, mut i2) = ($iter1, $iter2); // make variable mutable
-//         std::iter::from_fn(move || i1.next().or_else(|| i2.next()))
-//     }};
-//
-//     ($iter1: expr, $( $rest: expr),* ) => {
-//         concat_iter!($iter1, concat_iter!($($rest),*))
-//     };
-// }
+use crate::completion::Words;
+use crate::error::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
 
 pub fn char_vec(string: &str) -> Vec<char> {
     string.chars().collect()
 }
 
-pub fn tier2_only_variations<'s>(string: &'s [char]) -> impl Iterator<Item = String> + 's {
-    tier1_variations(string)
-        .flat_map(|variation| tier1_variations(&char_vec(&variation)))
+/// The longest prefix of a word whose deletions are indexed, as in SymSpell, which keeps the index of long words small.
+/// The rest of the word is only compared when the distance of a candidate is computed.
+const DELETION_PREFIX_LEN: usize = 7;
+
+/// All strings that result from deleting up to `max_distance` characters from the beginning of the word,
+/// including the beginning itself. Words that are similar to each other share at least one of these.
+pub fn deletions(word: &[char], max_distance: usize) -> HashSet<String> {
+    let prefix = &word[.. word.len().min(DELETION_PREFIX_LEN)];
+
+    let mut deletions: HashSet<String> = HashSet::new();
+    deletions.insert(prefix.iter().collect());
+
+    let mut current: Vec<Vec<char>> = vec![ prefix.to_vec() ];
+
+    for _ in 0 .. max_distance {
+        let mut next = Vec::new();
+
+        for chars in &current {
+            for index in 0 .. chars.len() {
+                let mut deleted = chars.clone();
+                deleted.remove(index);
+
+                if deletions.insert(deleted.iter().collect()) { next.push(deleted); }
+            }
+        }
+
+        current = next;
+    }
+
+    deletions
+}
+
+/// The number of deletions, insertions, substitutions and transpositions that turn one word into the other.
+pub fn distance(intended: &[char], typed: &[char]) -> usize {
+    edits(intended, typed).len()
+}
+
+/// A single typing error, which turns the intended word into the typed word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Edit {
+
+    /// A character was replaced by another one.
+    Substitute { intended: char, typed: char },
+
+    /// A character was left out, after the previous character, which is a space at the start of the word.
+    Delete { previous: char, intended: char },
+
+    /// An additional character was typed after the previous character, which is a space at the start of the word.
+    Insert { previous: char, typed: char },
+
+    /// Two neighbouring characters were typed in the wrong order.
+    Transpose { first: char, second: char },
+}
+
+/// A word that the typed prefix might have been meant to start with, along with how common it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correction {
+    pub word: String,
+    pub count: usize,
+    pub edit_distance: usize,
+
+    /// The probability of typing the typed prefix when the prefix of the word was intended.
+    pub likelihood: f64,
+}
+
+impl Correction {
+
+    /// How likely the word was intended, up to a factor that is the same for all corrections of the typed prefix.
+    pub fn weight(&self) -> f64 {
+        self.count as f64 * self.likelihood
+    }
+}
+
+/// The rows of the keyboard, along with how far they are shifted to the right, in keys.
+const KEYBOARD: &[(&str, f64)] = &[ ("1234567890", 0.0), ("qwertyuiop", 0.5), ("asdfghjkl", 0.75), ("zxcvbnm", 1.25) ];
+
+/// Characters that are often written instead of each other, because they sound alike.
+const CONFUSIONS: &[(char, char)] = &[
+    ('a', 'e'), ('e', 'i'), ('i', 'y'), ('o', 'u'), ('a', 'o'), ('c', 'k'), ('c', 's'), ('s', 'z'), ('m', 'n'), ('v', 'w'),
+    ('f', 'v'), ('d', 't'), ('b', 'p'), ('g', 'k'), ('ä', 'e'), ('ä', 'a'), ('ö', 'o'), ('ü', 'u'), ('ß', 's'),
+];
+
+// the probabilities of the edits, before learning from misspellings
+const ADJACENT_SUBSTITUTION: f64 = 0.01;
+const CONFUSED_SUBSTITUTION: f64 = 0.005;
+const SUBSTITUTION: f64 = 0.0005;
+const DOUBLED_DELETION: f64 = 0.01;
+const DELETION: f64 = 0.002;
+const ADJACENT_INSERTION: f64 = 0.005;
+const INSERTION: f64 = 0.0005;
+const TRANSPOSITION: f64 = 0.005;
+
+/// How many occurrences of the intended characters the initial probabilities are worth,
+/// compared to the occurrences in the learned corrections.
+const PRIOR_WEIGHT: f64 = 20.0;
+
+/// The probabilities of typing errors, the channel of a noisy-channel spelling corrector.
+/// Substitutions of neighbouring keys and of similar sounding characters are more likely than others.
+/// Learning from misspellings and their corrections moves the probabilities towards the observed errors.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorModel {
+
+    /// How often each edit occurred in the learned misspellings.
+    edits: HashMap<Edit, usize>,
+
+    /// How often each character and each pair of characters occurred in the learned corrections,
+    /// which is how often the edits could have occurred.
+    occurrences: HashMap<String, usize>,
+}
+
+impl ErrorModel {
+
+    /// Learn from a text file with a misspelling and its correction on each line, separated by whitespace.
+    /// Empty lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|error| Error::reading(path, error))?;
+        let mut model = ErrorModel::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                [ misspelling, correction ] => model.learn(misspelling, correction),
+                _ => return Err(Error::Typos(path.to_path_buf(), format!("line {} is not a misspelling followed by its correction", index + 1))),
+            }
+        }
+
+        println!("learned {} typing errors from {}", model.edits.values().sum::<usize>(), path.display());
+        Ok(model)
+    }
+
+    /// Count the edits of the misspelling, and the characters of the correction.
+    pub fn learn(&mut self, misspelling: &str, correction: &str) {
+        for edit in edits(&char_vec(correction), &char_vec(misspelling)) {
+            *self.edits.entry(edit).or_insert(0) += 1;
+        }
+
+        let padded = char_vec(&format!(" {}", correction));
+        for (index, &char) in padded.iter().enumerate() {
+            *self.occurrences.entry(char.to_string()).or_insert(0) += 1;
+
+            if let Some(&next) = padded.get(index + 1) {
+                *self.occurrences.entry([ char, next ].iter().collect()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// The probability of the edit, whenever its intended characters occur.
+    pub fn probability(&self, edit: Edit) -> f64 {
+        let observed = self.edits.get(&edit).cloned().unwrap_or(0) as f64;
+        let occurrences = self.occurrences.get(&intended(edit)).cloned().unwrap_or(0) as f64;
+        (observed + PRIOR_WEIGHT * prior(edit)) / (occurrences + PRIOR_WEIGHT)
+    }
+
+    /// The probability of typing the typed word when the intended word was meant, assuming the fewest possible errors.
+    pub fn likelihood(&self, intended: &str, typed: &str) -> f64 {
+        edits(&char_vec(intended), &char_vec(typed)).into_iter()
+            .map(|edit| self.probability(edit)).product()
+    }
+}
+
+/// The characters that must be intended for the edit to happen.
+fn intended(edit: Edit) -> String {
+    match edit {
+        Edit::Substitute { intended, .. } | Edit::Delete { intended, .. } => intended.to_string(),
+        Edit::Insert { previous, .. } => previous.to_string(),
+        Edit::Transpose { first, second } => [ first, second ].iter().collect(),
+    }
+}
+
+/// The probability of the edit before learning from misspellings.
+fn prior(edit: Edit) -> f64 {
+    match edit {
+        Edit::Substitute { intended, typed } if adjacent(intended, typed) => ADJACENT_SUBSTITUTION,
+        Edit::Substitute { intended, typed } if confused(intended, typed) => CONFUSED_SUBSTITUTION,
+        Edit::Substitute { .. } => SUBSTITUTION,
+        Edit::Delete { previous, intended } if previous == intended => DOUBLED_DELETION,
+        Edit::Delete { .. } => DELETION,
+        Edit::Insert { previous, typed } if previous == typed || adjacent(previous, typed) => ADJACENT_INSERTION,
+        Edit::Insert { .. } => INSERTION,
+        Edit::Transpose { .. } => TRANSPOSITION,
+    }
+}
+
+/// Whether the keys of the characters touch each other on the keyboard.
+fn adjacent(first: char, second: char) -> bool {
+    let position = |char: char| KEYBOARD.iter().enumerate().find_map(|(row, (keys, shift))|
+        keys.chars().position(|key| key == char).map(|column| (row as f64, column as f64 + shift))
+    );
+
+    match (position(first), position(second)) {
+        (Some((row, column)), Some((other_row, other_column))) => first != second
+            && (row - other_row).abs() <= 1.0 && (column - other_column).abs() <= 1.0,
+
+        _ => false,
+    }
+}
+
+fn confused(first: char, second: char) -> bool {
+    CONFUSIONS.iter().any(|&pair| pair == (first, second) || pair == (second, first))
+}
+
+/// The fewest edits that turn the intended word into the typed word,
+/// computed with the optimal string alignment distance.
+pub fn edits(intended: &[char], typed: &[char]) -> Vec<Edit> {
+    let (rows, columns) = (intended.len() + 1, typed.len() + 1);
+    let mut distances = vec![ 0_usize; rows * columns ];
+    let distance = |distances: &[usize], row: usize, column: usize| distances[row * columns + column];
+
+    for row in 0 .. rows {
+        for column in 0 .. columns {
+            distances[row * columns + column] = if row == 0 { column } else if column == 0 { row } else {
+                let substitution = if intended[row - 1] == typed[column - 1] { 0 } else { 1 };
+
+                let mut best = (distance(&distances, row - 1, column - 1) + substitution)
+                    .min(distance(&distances, row - 1, column) + 1)
+                    .min(distance(&distances, row, column - 1) + 1);
+
+                if row > 1 && column > 1 && intended[row - 1] == typed[column - 2] && intended[row - 2] == typed[column - 1] {
+                    best = best.min(distance(&distances, row - 2, column - 2) + 1);
+                }
+
+                best
+            };
+        }
+    }
+
+    // walk back from the end, collecting the edits of one of the shortest alignments
+    let mut edits = Vec::new();
+    let (mut row, mut column) = (intended.len(), typed.len());
+
+    // the character before the left out or additional character, or the character itself if it is doubled,
+    // as either of the doubled characters might be aligned with the single one
+    let repeated = |chars: &[char], index: usize| {
+        if chars.get(index + 1) == Some(&chars[index]) { chars[index] }
+        else if index == 0 { ' ' }
+        else { chars[index - 1] }
+    };
+
+    while row > 0 || column > 0 {
+        let current = distance(&distances, row, column);
+
+        if row > 0 && column > 0 && intended[row - 1] == typed[column - 1] && current == distance(&distances, row - 1, column - 1) {
+            row -= 1; column -= 1;
+        }
+        else if row > 1 && column > 1 && intended[row - 1] == typed[column - 2] && intended[row - 2] == typed[column - 1]
+            && current == distance(&distances, row - 2, column - 2) + 1
+        {
+            edits.push(Edit::Transpose { first: intended[row - 2], second: intended[row - 1] });
+            row -= 2; column -= 2;
+        }
+        else if row > 0 && column > 0 && current == distance(&distances, row - 1, column - 1) + 1 {
+            edits.push(Edit::Substitute { intended: intended[row - 1], typed: typed[column - 1] });
+            row -= 1; column -= 1;
+        }
+        else if row > 0 && current == distance(&distances, row - 1, column) + 1 {
+            edits.push(Edit::Delete { previous: repeated(intended, row - 1), intended: intended[row - 1] });
+            row -= 1;
+        }
+        else {
+            edits.push(Edit::Insert { previous: repeated(typed, column - 1), typed: typed[column - 1] });
+            column -= 1;
+        }
+    }
+
+    edits.reverse();
+    edits
+}
+
+/// Return a lambda that finds the words that are up to `max_distance` edits away from the typed word,
+/// or that start with up to `max_distance` edits of it, except for those that start with the typed word itself,
+/// most likely intended first, according to how common the words are and how likely the typing errors are.
+/// Corrections only add characters of the alphabet of the language, such that foreign words of the corpus are not suggested.
+pub fn corrector(words: Arc<Words>, errors: Arc<ErrorModel>) -> impl Fn(&str, usize) -> Vec<Correction> {
+    let alphabet: HashSet<char> = words.alphabet().into_iter().collect();
+
+    move |typed: &str, max_distance: usize| {
+        let spelled = |word: &str| word.chars().all(|char| alphabet.contains(&char) || typed.contains(char));
+
+        let whole_words = words.similar(typed, max_distance).into_iter()
+            .map(|(word, count, edit_distance)| Correction {
+                word: word.to_string(), count, edit_distance,
+                likelihood: errors.likelihood(word, typed),
+            });
+
+        // the typed word may be the misspelled beginning of a longer word
+        let completed_words = words.with_fuzzy_prefix(typed, max_distance).into_iter()
+            .map(|completion| Correction {
+                word: completion.word.to_string(), count: completion.count, edit_distance: completion.distance,
+                likelihood: errors.likelihood(completion.prefix, typed),
+            });
+
+        let mut best: HashMap<String, Correction> = HashMap::new();
+
+        let corrections = whole_words.chain(completed_words)
+            .filter(|correction| !correction.word.starts_with(typed) && spelled(&correction.word));
+
+        for correction in corrections {
+            match best.get(&correction.word) {
+                Some(other) if other.weight() >= correction.weight() => {},
+                _ => { best.insert(correction.word.clone(), correction); },
+            }
+        }
+
+        let mut corrections: Vec<Correction> = best.into_values().collect();
+        corrections.sort_by(|a, b| b.weight().partial_cmp(&a.weight()).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.word.cmp(&b.word)));
+        corrections
+    }
 }
 
-pub fn tier1_variations(word: &[char]) -> impl Iterator<Item = String> {
-    const CHARS: &'static str = "abcdefghijklmnopqrstuvwxyz";
-
-    // variations that have one of the chars deleted
-    let deletes = (0..word.len()).map(|delete_at|{
-        let slice = [ &word[..delete_at], &word[delete_at + 1..] ];
-        slice.iter().flat_map(|slice| slice.into_iter()).collect()
-    });
-
-    // variations that have two neighbouring chars swapped
-    let swaps = (0..word.len() - 1).map(|left_idx|{
-        let slice = [
-            &word[..left_idx],
-            &[word[left_idx + 1], word[left_idx]],
-            &word[left_idx + 2..]
-        ];
-
-        slice.iter().flat_map(|slice| slice.into_iter()).collect()
-    });
-
-    // variations that have char replaced
-    let replaces = (0..word.len()).flat_map(|insert_at|{
-        CHARS.chars().map(move |insert_letter|{
-            let slice = [ &word[..insert_at], &[insert_letter], &word[insert_at + 1..] ];
-            slice.iter().flat_map(|slice| slice.into_iter()).collect()
-        })
-    });
-
-    // variations that have an additional char inserted
-    let inserts = (0..=word.len()).flat_map(|insert_at|{
-        CHARS.chars().map(move |insert_letter|{
-            let slice = [ &word[..insert_at], &[insert_letter], &word[insert_at..] ];
-            slice.iter().flat_map(|slice| slice.into_iter()).collect()
-        })
-    });
-
-    deletes.chain(swaps).chain(replaces).chain(inserts)
-        .collect::<Vec<String>>().into_iter()
-    // concat_iter!(deletes, swaps, replaces, inserts)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edits_of(intended: &str, typed: &str) -> Vec<Edit> {
+        edits(&char_vec(intended), &char_vec(typed))
+    }
+
+    #[test]
+    fn aligns_a_transposition_as_one_edit() {
+        assert_eq!(edits_of("form", "from"), vec![ Edit::Transpose { first: 'o', second: 'r' } ]);
+        assert_eq!(edits_of("the", "hte"), vec![ Edit::Transpose { first: 't', second: 'h' } ]);
+        assert_eq!(distance(&char_vec("form"), &char_vec("from")), 1);
+    }
+
+    #[test]
+    fn aligns_other_edits_after_their_previous_character() {
+        assert_eq!(edits_of("word", "wprd"), vec![ Edit::Substitute { intended: 'o', typed: 'p' } ]);
+        assert_eq!(edits_of("word", "wrd"), vec![ Edit::Delete { previous: 'w', intended: 'o' } ]);
+        assert_eq!(edits_of("word", "sword"), vec![ Edit::Insert { previous: ' ', typed: 's' } ]);
+        assert_eq!(edits_of("letter", "leter"), vec![ Edit::Delete { previous: 't', intended: 't' } ]);
+        assert!(edits_of("word", "word").is_empty());
+    }
+
+    #[test]
+    fn prefers_substitutions_of_neighbouring_keys() {
+        let errors = ErrorModel::default();
+        let neighbouring = errors.probability(Edit::Substitute { intended: 'e', typed: 'w' });
+        let distant = errors.probability(Edit::Substitute { intended: 'e', typed: 'p' });
+
+        assert!(neighbouring > distant, "{} > {}", neighbouring, distant);
+        assert!(errors.likelihood("test", "twst") > errors.likelihood("test", "tpst"));
+    }
+
+    #[test]
+    fn learns_the_observed_errors() {
+        let mut errors = ErrorModel::default();
+        let edit = Edit::Substitute { intended: 'e', typed: 'p' };
+        let before = errors.probability(edit);
+
+        errors.learn("tpst", "test");
+        assert!(errors.probability(edit) > before);
+    }
+
+    #[test]
+    fn reports_the_line_of_a_malformed_misspelling() {
+        let path = std::env::temp_dir().join(format!("text-analysis-typos-{}.txt", std::process::id()));
+        std::fs::write(&path, "# misspelling correction\nteh the\n\nrecieve\n").unwrap();
+
+        let result = ErrorModel::from_file(&path);
+        let _ = std::fs::remove_file(&path);
+
+        match result {
+            Err(Error::Typos(file, message)) => {
+                assert_eq!(file, path);
+                assert!(message.contains("line 4"), "{}", message);
+            },
+
+            other => panic!("expected a typos error, got {:?}", other.map(|_| ())),
+        }
+    }
 }
@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong while reading the corpus or reading and writing the caches.
+/// Each variant remembers which file caused the error, if a single file did.
+#[derive(Debug)]
+pub enum Error {
+
+    /// A file or directory could not be opened, read or written.
+    Io(PathBuf, std::io::Error),
+
+    /// A corpus file does not contain valid UTF-8 text.
+    Encoding(PathBuf),
+
+    /// A cache file could not be written.
+    Serialization(PathBuf, bincode::Error),
+
+    /// A cache file exists, but is corrupted, outdated, or was built with other parameters.
+    InvalidCache(PathBuf, String),
+
+    /// The corpus configuration file or the patterns within are invalid.
+    Config(PathBuf, String),
+
+    /// The file of misspellings and their corrections contains an invalid line.
+    Typos(PathBuf, String),
+
+    /// The corpus contains more distinct words than the prediction model can refer to.
+    Vocabulary(usize),
+
+    /// The server cannot listen on the address.
+    Address(String, String),
+}
+
+impl Error {
+
+    /// Classify an error that occurred while reading the text file at that path.
+    pub fn reading(path: impl Into<PathBuf>, error: std::io::Error) -> Self {
+        if error.kind() == std::io::ErrorKind::InvalidData { Error::Encoding(path.into()) }
+        else { Error::Io(path.into(), error) }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, error) => write!(formatter, "{}: {}", path.display(), error),
+            Error::Encoding(path) => write!(formatter, "{}: file is not valid UTF-8", path.display()),
+            Error::Serialization(path, error) => write!(formatter, "{}: cannot write cache: {}", path.display(), error),
+            Error::InvalidCache(path, reason) => write!(formatter, "{}: invalid cache: {} (run the build command to replace it)", path.display(), reason),
+            Error::Config(path, message) => write!(formatter, "{}: invalid corpus configuration: {}", path.display(), message),
+            Error::Typos(path, message) => write!(formatter, "{}: invalid misspellings file: {}", path.display(), message),
+            Error::Address(address, message) => write!(formatter, "{}: cannot start server: {}", address, message),
+            Error::Vocabulary(max_words) => write!(formatter, "the corpus contains more than {} distinct words, which cannot be predicted", max_words),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, error) => Some(error),
+            Error::Serialization(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
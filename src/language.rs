@@ -0,0 +1,140 @@
+//! Identifying the language of the typed text from the character n-grams of its words.
+//!
+//! The completion cache of each language stores the most common character n-grams of its words,
+//! counted as often as the words occur in the corpus. A text is assigned to the language
+//! whose n-gram probabilities explain its words best, if that language is likely enough.
+
+use std::collections::HashMap;
+
+/// The longest character n-grams that are counted.
+pub const MAX_NGRAM_LEN: usize = 3;
+
+/// How many of the most common n-grams of each length are stored.
+const PROFILE_SIZE: usize = 1000;
+
+/// The probability of an n-gram that is not part of the profile, the same for all languages,
+/// such that languages with a smaller corpus are not preferred for rare n-grams.
+const UNSEEN_PROBABILITY: f64 = 1e-5;
+
+/// The character n-grams of the word, from single characters up to `MAX_NGRAM_LEN` characters.
+/// Longer n-grams include a space before and after the word, such that they capture how words start and end.
+pub fn ngrams(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let padded: Vec<char> = std::iter::once(' ').chain(chars.iter().cloned()).chain(std::iter::once(' ')).collect();
+
+    let mut ngrams: Vec<String> = chars.iter().map(char::to_string).collect();
+
+    for len in 2 ..= MAX_NGRAM_LEN {
+        ngrams.extend(padded.windows(len).map(|window| window.iter().collect::<String>()));
+    }
+
+    ngrams
+}
+
+/// Count the n-grams of the words, and keep the most common n-grams of each length, sorted by n-gram.
+pub fn most_common<'w>(words: impl Iterator<Item = (&'w str, usize)>) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+
+    for (word, count) in words {
+        for ngram in ngrams(word) { *counts.entry(ngram).or_insert(0) += count; }
+    }
+
+    let mut by_len: Vec<Vec<(String, usize)>> = vec![ Vec::new(); MAX_NGRAM_LEN ];
+    for (ngram, count) in counts {
+        by_len[ngram.chars().count() - 1].push((ngram, count));
+    }
+
+    let mut profile: Vec<(String, usize)> = by_len.into_iter()
+        .flat_map(|mut ngrams| {
+            ngrams.sort_by(|(ngram, count), (other, other_count)| other_count.cmp(count).then(ngram.cmp(other)));
+            ngrams.truncate(PROFILE_SIZE);
+            ngrams
+        })
+        .collect();
+
+    profile.sort();
+    profile
+}
+
+/// The probabilities of the character n-grams of a language, relative to the other n-grams of the same length.
+pub struct Profile {
+    log_probabilities: HashMap<String, f64>,
+}
+
+impl Profile {
+    pub fn new<'n>(ngrams: impl Iterator<Item = (&'n str, usize)>) -> Self {
+        let ngrams: Vec<(&str, usize, usize)> = ngrams
+            .map(|(ngram, count)| (ngram, count, ngram.chars().count()))
+            .filter(|&(_, _, len)| (1 ..= MAX_NGRAM_LEN).contains(&len))
+            .collect();
+
+        let mut totals = [0_usize; MAX_NGRAM_LEN];
+        for &(_, count, len) in &ngrams { totals[len - 1] += count; }
+
+        let log_probabilities = ngrams.iter()
+            .map(|&(ngram, count, len)| (ngram.to_string(), (count as f64 / totals[len - 1] as f64).max(UNSEEN_PROBABILITY).ln()))
+            .collect();
+
+        Profile { log_probabilities }
+    }
+
+    /// The log probability of all n-grams of the words.
+    /// As the n-grams of different lengths overlap, each length only contributes its share.
+    fn log_likelihood(&self, ngrams: &[String]) -> f64 {
+        let sum: f64 = ngrams.iter()
+            .map(|ngram| self.log_probabilities.get(ngram).cloned().unwrap_or_else(|| UNSEEN_PROBABILITY.ln()))
+            .sum();
+
+        sum / MAX_NGRAM_LEN as f64
+    }
+}
+
+/// Chooses the language of a text among the languages of the corpus.
+pub struct Identifier {
+    languages: Vec<(String, Profile)>,
+
+    /// The minimum probability of the most likely language, below which the fallback language is chosen.
+    threshold: f64,
+    fallback: String,
+}
+
+impl Identifier {
+    pub fn new(threshold: f64, fallback: String) -> Self {
+        Identifier { languages: Vec::new(), threshold, fallback }
+    }
+
+    pub fn add(&mut self, language: String, profile: Profile) {
+        self.languages.push((language, profile));
+    }
+
+    /// The language of the words, or the fallback language if no language is likely enough.
+    pub fn identify(&self, words: &[String]) -> &str {
+        match self.most_likely(words) {
+            Some((language, probability)) if probability >= self.threshold => {
+                println!("identified language {} with probability {:.3}", language, probability);
+                language
+            },
+
+            most_likely => {
+                println!("uncertain language {:?}, using {}", most_likely, self.fallback);
+                &self.fallback
+            },
+        }
+    }
+
+    /// The most likely language of the words, along with its probability,
+    /// assuming that all languages are equally likely before seeing the words.
+    fn most_likely(&self, words: &[String]) -> Option<(&str, f64)> {
+        let ngrams: Vec<String> = words.iter().flat_map(|word| ngrams(word)).collect();
+        if ngrams.is_empty() { return None; }
+
+        let log_likelihoods: Vec<f64> = self.languages.iter()
+            .map(|(_, profile)| profile.log_likelihood(&ngrams)).collect();
+
+        let (best, &max) = log_likelihoods.iter().enumerate()
+            .max_by(|(_, likelihood), (_, other)| likelihood.partial_cmp(other).unwrap())?;
+
+        let sum: f64 = log_likelihoods.iter().map(|likelihood| (likelihood - max).exp()).sum();
+        Some((&self.languages[best].0, 1.0 / sum))
+    }
+}
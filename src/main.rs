@@ -10,84 +10,879 @@ mod completion;
 mod server;
 mod prediction;
 mod correction;
+mod statistics;
+mod error;
+mod cache;
+mod builder;
+mod mapped;
+mod tokenizer;
+mod sentences;
+mod language;
 
-use crate::corpus::split_to_words;
-use crate::server::{Response, Request};
-use std::io::Write;
-use crate::correction::{char_vec, tier1_variations, tier2_only_variations};
+use crate::corpus::{CorpusConfig, ErrorPolicy};
+use crate::server::{PhraseSuggestion, Response, Request, Suggestion, SuggestionSource};
+use crate::completion::Completion;
+use crate::prediction::{Phrase, Prediction, Pruning, Smoothing};
+use crate::correction::{Correction, ErrorModel};
+use crate::cache::Rebuild;
+use crate::builder::{ModelBuilder, Models};
+use crate::language::{Identifier, Profile};
+use crate::tokenizer::{Tokenizer, UnicodeTokenizer};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::path::{PathBuf, Path};
+use std::sync::Arc;
 
 
+/// Where the corpus and the caches are located, and how many suggestions are computed.
+pub struct Options {
+    pub corpus: CorpusConfig,
+    pub completion_cache: PathBuf,
+    pub prediction_cache: PathBuf,
+    pub order: usize,
+    pub count: usize,
+
+    /// The maximum number of words of the suggested phrases, which are not suggested if this is less than two.
+    pub phrase_length: usize,
+
+    /// How many of the most likely phrases of each length are extended by the next word.
+    pub beam_width: usize,
+    pub smoothing: Smoothing,
+    pub pruning: Pruning,
+
+    /// The minimum probability of the identified language of a request, below which the fallback language is used.
+    pub language_threshold: f64,
+
+    /// The language of the requests whose language cannot be identified, the first language of the corpus by default.
+    pub fallback_language: String,
+
+    /// The maximum number of edits between a typed word and its corrections, for which the correction index is built.
+    pub max_edit_distance: usize,
+
+    /// The probabilities of typing errors, for ranking the corrections of the typed word.
+    pub errors: Arc<ErrorModel>,
+
+    /// Splits the corpus and the queries into words.
+    pub tokenizer: Arc<dyn Tokenizer>,
+}
+
 fn main() {
-    let respond = responder();
+    let matches = App::new("text-analysis")
+        .about("Word completion and prediction based on text corpora")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+
+        .arg(Arg::with_name("corpus").long("corpus").global(true).takes_value(true).multiple(true).number_of_values(1)
+            .default_value("corpora").help("A directory (or single file) containing the text files, may be repeated"))
+
+        .arg(Arg::with_name("corpus-config").long("corpus-config").global(true).takes_value(true)
+            .help("A TOML or JSON file listing the corpus sources, replacing --corpus, --include and --exclude"))
+
+        .arg(Arg::with_name("include").long("include").global(true).takes_value(true).multiple(true).number_of_values(1)
+            .help("Glob pattern of the corpus files to read, defaults to **/*.txt"))
+
+        .arg(Arg::with_name("exclude").long("exclude").global(true).takes_value(true).multiple(true).number_of_values(1)
+            .help("Glob pattern of the corpus files and directories to skip, defaults to **/_*"))
+
+        .arg(Arg::with_name("language").long("language").global(true).takes_value(true)
+            .help("Only use the corpus sources of this language, or the language of the --corpus directories"))
+
+        .arg(Arg::with_name("language-threshold").long("language-threshold").global(true).takes_value(true)
+            .default_value("0.8").help("How likely the identified language of a request must be, between 0 and 1"))
+
+        .arg(Arg::with_name("fallback-language").long("fallback-language").global(true).takes_value(true)
+            .help("The language of requests whose language cannot be identified, defaults to the first language of the corpus"))
+
+        .arg(Arg::with_name("max-edit-distance").long("max-edit-distance").global(true).takes_value(true)
+            .default_value("2").help("The maximum number of typing errors that are corrected in a word, at most 3"))
+
+        .arg(Arg::with_name("typos").long("typos").global(true).takes_value(true)
+            .help("A text file with a misspelling and its correction on each line, for learning which typing errors are common"))
+
+        .arg(Arg::with_name("completion-cache").long("completion-cache").global(true).takes_value(true)
+            .default_value(".completion-cache").help("Where the word completion data base is stored"))
+
+        .arg(Arg::with_name("prediction-cache").long("prediction-cache").global(true).takes_value(true)
+            .default_value(".prediction-cache").help("Where the word prediction data base is stored"))
+
+        .arg(Arg::with_name("on-error").long("on-error").global(true).takes_value(true)
+            .possible_values(&["skip", "abort"]).default_value("skip").help("Whether to skip or to abort on unreadable corpus files"))
+
+        .arg(Arg::with_name("order").long("order").global(true).takes_value(true)
+            .default_value("2").help("The maximum number of previous words used to predict the next word, at most 5"))
+
+        .arg(Arg::with_name("count").long("count").global(true).takes_value(true)
+            .default_value("7").help("The number of suggestions to compute"))
+
+        .arg(Arg::with_name("phrase-length").long("phrase-length").global(true).takes_value(true)
+            .default_value("3").help("The maximum number of words of the suggested phrases, less than two disables them"))
+
+        .arg(Arg::with_name("beam-width").long("beam-width").global(true).takes_value(true)
+            .default_value("5").help("How many of the most likely phrases of each length are extended by another word"))
 
-    let _ = respond(Request {
-        previous: "hi thr".to_string(),
-        next: "".to_string()
-    });
+        .arg(Arg::with_name("smoothing").long("smoothing").global(true).takes_value(true)
+            .possible_values(&["heuristic", "kneser-ney"]).default_value("heuristic")
+            .help("How the probabilities of the predicted words are estimated"))
 
-    // start a server that returns suggestions
-    // server::run(respond());
+        .arg(Arg::with_name("min-count").long("min-count").global(true).takes_value(true)
+            .help("The minimum count of the n-grams of each order, separated by commas, starting with single words. The last value applies to all higher orders"))
+
+        .arg(Arg::with_name("single-successors").long("single-successors").global(true)
+            .help("Remove the contexts of multiple words that have a single successor"))
+
+        .arg(Arg::with_name("top-successors").long("top-successors").global(true).takes_value(true)
+            .help("How many of the most common successors of each context are kept"))
+
+        .arg(Arg::with_name("entropy-threshold").long("entropy-threshold").global(true).takes_value(true)
+            .help("Remove the n-grams that barely change the predictions, for example 1e-7"))
+
+        .arg(Arg::with_name("target-size").long("target-size").global(true).takes_value(true)
+            .help("Remove the least common n-grams until the prediction cache fits into this many megabytes"))
+
+        .arg(Arg::with_name("keep-digits").long("keep-digits").global(true)
+            .help("Keep words that contain digits, like 3D or 1,000"))
+
+        .arg(Arg::with_name("keep-hyphens").long("keep-hyphens").global(true)
+            .help("Keep hyphenated compounds like e-mail as a single word"))
+
+        .arg(Arg::with_name("split-contractions").long("split-contractions").global(true)
+            .help("Split contractions like don't into two words"))
+
+        .arg(Arg::with_name("keep-case").long("keep-case").global(true)
+            .help("Do not convert the words to lowercase"))
+
+        .subcommand(SubCommand::with_name("build")
+            .about("Analyze the corpus and write the completion and prediction caches, unless they are up to date")
+            .arg(Arg::with_name("force-rebuild").long("force-rebuild").help("Analyze the corpus even if the caches are up to date"))
+            .arg(Arg::with_name("statistics").long("statistics").takes_value(true)
+                .help("Also write the word and character statistics into this directory, within the same analysis")))
+
+        .subcommand(SubCommand::with_name("append")
+            .about("Merge the new corpus files of a directory into the existing caches, without analyzing the whole corpus")
+            .arg(Arg::with_name("directory").required(true).help("A directory within the corpus containing the new text files")))
+
+        .subcommand(SubCommand::with_name("serve")
+            .about("Start a server that answers JSON suggestion requests")
+            .arg(Arg::with_name("address").long("address").takes_value(true).default_value("localhost:3000")))
+
+        .subcommand(SubCommand::with_name("query")
+            .about("Print the suggestions for the text before the cursor")
+            .arg(Arg::with_name("previous").required(true).help("The text before the cursor"))
+            .arg(Arg::with_name("next").long("next").takes_value(true).default_value("").help("The text after the cursor")))
+
+        .subcommand(SubCommand::with_name("perplexity")
+            .about("Compare the perplexity of the smoothing methods on held-out text, which should not be part of the corpus")
+            .arg(Arg::with_name("held-out").required(true).help("A directory (or single file) containing the held-out text files")))
+
+        .subcommand(SubCommand::with_name("stats")
+            .about("Write the word and character statistics of the corpus")
+            .arg(Arg::with_name("output").long("output").takes_value(true).default_value("results")))
+
+        .get_matches();
+
+    let (command, arguments) = matches.subcommand();
+    let arguments = arguments.expect("subcommand required");
+    let options = options(arguments);
+
+    if let Err(error) = run(command, arguments, &options) {
+        eprintln!("Error: {}", error);
+        std::process::exit(1);
+    }
 }
 
-fn responder() -> impl Fn(Request) -> Response {
-    println!("preparing data bases...");
+fn run(command: &str, arguments: &ArgMatches, options: &Options) -> error::Result<()> {
+    match command {
+        "build" => {
+            let rebuild = if arguments.is_present("force-rebuild") { Rebuild::Always } else { Rebuild::IfInvalid };
+            let statistics = arguments.value_of("statistics").map(Path::new);
 
-    let complete = completion::build();
-    let predict = prediction::ngram_predictor();
+            for language in options.corpus.languages() {
+                let output = statistics.map(|output| output.join(&language));
+                let builder = ModelBuilder::new(options, &language, rebuild);
 
-    println!("... prepared databases");
+                let builder = match &output {
+                    Some(output) => builder.statistics(output),
+                    None => builder,
+                };
 
-    let respond = move |request: Request|{
-        let mut previous_words = split_to_words(&request.previous);
-        let last_word = previous_words.last().cloned().unwrap_or(String::new());
+                builder.build()?;
+            }
+        },
 
-        println!("requesting suggestions for word: {:?}", previous_words);
+        "append" => {
+            let directory = Path::new(arguments.value_of("directory").unwrap());
 
-        let mut predicted_completions: Vec<String> = predict(&previous_words[ .. previous_words.len() - 1 ]);
-        println!("unfiltered predicted based on all but the last word: {:?}", predicted_completions);
+            for language in options.corpus.languages() {
+                let corpus = options.corpus.language(&language);
+                let completion_cache = cache::language_path(&options.completion_cache, &language);
+                let prediction_cache = cache::language_path(&options.prediction_cache, &language);
 
-        predicted_completions.retain(|word| {
-            word.starts_with(&last_word) || levenshtein::levenshtein(
-                &last_word, &word[.. word.len().min(last_word.len())]
-            ) < 3
-        });
+                completion::append(&corpus, &completion_cache, &*options.tokenizer, options.max_edit_distance, directory)?;
+                prediction::append(&corpus, &prediction_cache, options.order, &options.pruning, &*options.tokenizer, directory)?;
+            }
+        },
 
-        println!("filtered predicted based on all but the last word: {:?}", predicted_completions);
+        "serve" => {
+            server::run(arguments.value_of("address").unwrap(), responder(options)?)?;
+        },
 
-        let mut char_completions: Vec<String> = complete(&last_word).into_iter().take(7).collect();
-        println!("char completions: {:?}", char_completions);
+        "query" => {
+            let respond = responder(options)?;
 
-        if char_completions.len() < 7 && last_word.len() > 2 {
-            let lev_completions: Vec<String> = tier1_variations(&char_vec(&last_word))
-                .flat_map(|prefix| complete(&prefix).first().cloned().into_iter())
-                .take(7).collect();
+            let response = respond(Request {
+                previous: arguments.value_of("previous").unwrap().to_string(),
+                next: arguments.value_of("next").unwrap().to_string(),
+                language: None,
+            });
 
-            println!("lev1 completions: {:?}", lev_completions);
-            char_completions.extend(lev_completions.into_iter())
-        }
+            println!("{}", serde_json::to_string_pretty(&response).unwrap());
+        },
+
+        "perplexity" => {
+            let held_out = CorpusConfig::from_roots(
+                vec![ PathBuf::from(arguments.value_of("held-out").unwrap()) ],
+                None, None, "", options.corpus.on_error
+            )?;
 
-        if char_completions.len() < 7 && last_word.len() > 3 {
-            let lev_completions: Vec<String> = tier2_only_variations(&char_vec(&last_word))
-                .flat_map(|prefix| complete(&prefix).first().cloned().into_iter())
-                .take(7).collect();
+            // the held-out text is in the first language, unless another language is selected
+            let language = options.corpus.languages().remove(0);
+            let model = ModelBuilder::new(options, &language, Rebuild::IfOutdated).build()?.prediction;
+            let evaluation = model.evaluate(&held_out, &*options.tokenizer)?;
+
+            println!("evaluated {} words, {} of which are unknown", evaluation.words, evaluation.unknown_words);
+            println!("heuristic perplexity: {:.2}", evaluation.heuristic);
+            println!("kneser-ney perplexity: {:.2}", evaluation.kneser_ney);
+        },
+
+        "stats" => {
+            let output = Path::new(arguments.value_of("output").unwrap());
+
+            for language in options.corpus.languages() {
+                statistics::write_reports(&options.corpus.language(&language), &*options.tokenizer, &output.join(&language))?;
+            }
+        },
+
+        _ => unreachable!("unknown subcommand {}", command),
+    }
+
+    Ok(())
+}
 
-            println!("lev2 completions: {:?}", lev_completions);
-            char_completions.extend(lev_completions.into_iter())
+/// Read the global options, exiting with a message if a number or the corpus configuration is invalid.
+fn options(arguments: &ArgMatches) -> Options {
+    let number = |name: &str| arguments.value_of(name).unwrap().parse::<usize>()
+        .unwrap_or_else(|_| clap::Error::value_validation_auto(format!("--{} must be a number", name)).exit());
+
+    let optional = |name: &str| arguments.value_of(name).map(|value| value.parse::<f64>()
+        .ok().filter(|value| *value >= 0.0)
+        .unwrap_or_else(|| clap::Error::value_validation_auto(format!("--{} must be a positive number", name)).exit()));
+
+    let strings = |name: &str| arguments.values_of(name)
+        .map(|values| values.map(str::to_string).collect::<Vec<String>>());
+
+    let on_error = match arguments.value_of("on-error") {
+        Some("abort") => ErrorPolicy::Abort,
+        _ => ErrorPolicy::Skip,
+    };
+
+    let language = arguments.value_of("language");
+
+    let corpus = match arguments.value_of("corpus-config") {
+        Some(path) => CorpusConfig::from_file(Path::new(path)).map(|config| CorpusConfig {
+            on_error: if arguments.occurrences_of("on-error") > 0 { on_error } else { config.on_error },
+            .. config
+        }),
+
+        None => CorpusConfig::from_roots(
+            arguments.values_of("corpus").unwrap().map(PathBuf::from).collect(),
+            strings("include"), strings("exclude"), language.unwrap_or(""), on_error
+        ),
+    };
+
+    let corpus = corpus.unwrap_or_else(|error|
+        clap::Error::value_validation_auto(error.to_string()).exit()
+    );
+
+    let corpus = match language {
+        Some(language) if !corpus.sources.iter().any(|source| source.language == language) =>
+            clap::Error::value_validation_auto(format!("the corpus contains no sources of the language {}", language)).exit(),
+
+        Some(language) => corpus.language(language),
+        None => corpus,
+    };
+
+    let languages = corpus.languages();
+    let fallback_language = match arguments.value_of("fallback-language") {
+        Some(language) if !languages.iter().any(|name| name == language) =>
+            clap::Error::value_validation_auto(format!("the corpus contains no sources of the fallback language {}", language)).exit(),
+
+        Some(language) => language.to_string(),
+        None => languages[0].clone(),
+    };
+
+    Options {
+        corpus,
+        completion_cache: PathBuf::from(arguments.value_of("completion-cache").unwrap()),
+        prediction_cache: PathBuf::from(arguments.value_of("prediction-cache").unwrap()),
+        order: match number("order") {
+            order if order > prediction::MAX_CHAIN_LEN => clap::Error::value_validation_auto(
+                format!("--order must not be greater than {}", prediction::MAX_CHAIN_LEN)
+            ).exit(),
+
+            order => order.max(1),
+        },
+        count: number("count"),
+        phrase_length: number("phrase-length"),
+        beam_width: number("beam-width").max(1),
+
+        smoothing: match arguments.value_of("smoothing") {
+            Some("kneser-ney") => Smoothing::KneserNey,
+            _ => Smoothing::Heuristic,
+        },
+
+        pruning: Pruning {
+            min_counts: arguments.value_of("min-count").map(|counts| counts.split(',')
+                .map(|count| count.trim().parse::<usize>().unwrap_or_else(|_|
+                    clap::Error::value_validation_auto("--min-count must be a list of numbers".to_string()).exit()
+                ))
+                .collect()
+            ).unwrap_or_default(),
+
+            single_successors: arguments.is_present("single-successors"),
+            top_successors: arguments.value_of("top-successors").map(|_| number("top-successors")),
+            entropy_threshold: optional("entropy-threshold"),
+            target_size: optional("target-size").map(|megabytes| (megabytes * 1024.0 * 1024.0) as usize),
+        },
+
+        language_threshold: optional("language-threshold").filter(|threshold| *threshold <= 1.0)
+            .unwrap_or_else(|| clap::Error::value_validation_auto("--language-threshold must be between 0 and 1".to_string()).exit()),
+
+        fallback_language,
+
+        max_edit_distance: match number("max-edit-distance") {
+            distance if distance > 3 => clap::Error::value_validation_auto("--max-edit-distance must not be greater than 3".to_string()).exit(),
+            distance => distance,
+        },
+
+        errors: Arc::new(match arguments.value_of("typos") {
+            Some(path) => ErrorModel::from_file(Path::new(path))
+                .unwrap_or_else(|error| clap::Error::value_validation_auto(error.to_string()).exit()),
+
+            None => ErrorModel::default(),
+        }),
+
+        tokenizer: Arc::new(UnicodeTokenizer {
+            digits: arguments.is_present("keep-digits"),
+            hyphens: arguments.is_present("keep-hyphens"),
+            split_contractions: arguments.is_present("split-contractions"),
+            case: arguments.is_present("keep-case"),
+        }),
+    }
+}
+
+/// Prepare the models of all languages, and answer each request with the models of its language,
+/// which is identified from the text before the cursor unless the request specifies it.
+fn responder(options: &Options) -> error::Result<impl Fn(Request) -> Response> {
+    let mut languages: Vec<(String, LanguageResponder)> = Vec::new();
+    let mut identifier = Identifier::new(options.language_threshold, options.fallback_language.clone());
+
+    for language in options.corpus.languages() {
+        println!("preparing data bases...");
+        let models = ModelBuilder::new(options, &language, Rebuild::IfOutdated).build()?;
+        identifier.add(language.clone(), Profile::new(models.words.ngrams()));
+
+        let respond = language_responder(options, &language, models);
+        languages.push((language, Box::new(respond)));
+        println!("... prepared databases");
+    }
+
+    let tokenizer = options.tokenizer.clone();
+
+    Ok(move |request: Request| {
+        let respond = match &request.language {
+            Some(language) => languages.iter().find(|(name, _)| name == language),
+            None if languages.len() == 1 => languages.first(),
+
+            None => {
+                let language = identifier.identify(&tokenizer.words(&request.previous));
+                languages.iter().find(|(name, _)| name == language)
+            },
+        };
+
+        match respond {
+            Some((_, respond)) => respond(&request),
+
+            None => {
+                println!("no models for language {:?}", request.language);
+                Response { completions: Vec::new(), predictions: Vec::new(), phrases: Vec::new(), language: String::new() }
+            },
         }
+    })
+}
+
+/// Answers the requests of a single language.
+type LanguageResponder = Box<dyn Fn(&Request) -> Response>;
+
+fn language_responder(options: &Options, language: &str, models: Models) -> impl Fn(&Request) -> Response {
+    let count = options.count;
+    let language = language.to_string();
+    let tokenizer = options.tokenizer.clone();
+    let errors = options.errors.clone();
+    let vocabulary = Arc::new(models.words);
+    let complete = completion::completer(vocabulary.clone());
+    let correct = correction::corrector(vocabulary.clone(), errors.clone());
+    let max_edit_distance = options.max_edit_distance;
+    let form = completion::former(vocabulary);
+    let model = Arc::new(models.prediction);
+    let predict = prediction::ngram_predictor(model.clone(), options.count, options.smoothing);
+    let predict_phrases = prediction::phrase_predictor(model.clone(), options.phrase_length, options.beam_width, options.smoothing);
+    let fit = prediction::right_context_scorer(model, options.smoothing);
+
+    let respond = move |request: &Request|{
+        let previous_words = tokenizer.words(&request.previous);
+        let (last_word, completed_previous) = match previous_words.split_last() {
+            Some((last_word, completed_previous)) => (last_word.clone(), completed_previous),
+            None => return Response { completions: Vec::new(), predictions: Vec::new(), phrases: Vec::new(), language: language.clone() },
+        };
+
+        // the words after the cursor, except the rest of the word in which the cursor is placed
+        let mut next_words = tokenizer.words(&sentences::first_sentence(&request.next));
+        if request.next.starts_with(char::is_alphanumeric) && !next_words.is_empty() { next_words.remove(0); }
+
+        println!("requesting suggestions for word: {:?}, followed by {:?}", previous_words, next_words);
+
+        let predicted_completions: Vec<Prediction> = predict(completed_previous);
+        println!("unfiltered predicted based on all but the last word: {:?}",
+            predicted_completions.iter().map(|prediction| &prediction.word).collect::<Vec<_>>());
+
+        let predicted_completions: Vec<Suggestion> = predicted_completions.into_iter()
+            .filter_map(|prediction| {
+                if prediction.word.starts_with(&last_word) { return Some(predicted_suggestion(prediction, 0, 1.0)); }
+
+                let prefix: String = prediction.word.chars().take(last_word.chars().count()).collect();
+                let distance = levenshtein::levenshtein(&last_word, &prefix);
+                let likelihood = errors.likelihood(&prefix, &last_word);
+
+                if distance < 3 { Some(predicted_suggestion(prediction, distance, likelihood)) } else { None }
+            })
+            .collect();
+
+        println!("filtered predicted based on all but the last word: {:?}", words(&predicted_completions));
+
+        let exact_completions: Vec<Completion> = complete(&last_word).into_iter().take(count).collect();
+        println!("char completions: {:?}", exact_completions.iter().map(|completion| &completion.word).collect::<Vec<_>>());
+
+        // the typed word might be misspelled if few words start with it, and longer words might contain more typing errors
+        let max_distance = match last_word.chars().count() { 0 ..= 2 => 0, 3 ..= 4 => 1, 5 ..= 8 => 2, _ => 3 }.min(max_edit_distance);
+
+        let mut corrections = if exact_completions.len() < count && max_distance > 0 { correct(&last_word, max_distance) } else { Vec::new() };
+        corrections.truncate(count);
+        println!("corrections: {:?}", corrections.iter().map(|correction| &correction.word).collect::<Vec<_>>());
+
+        // the probability that each word was intended, given the typed prefix, where the typed prefix itself needs no edits
+        let prefix_count = exact_completions.first().map(|completion| completion.prefix_count).unwrap_or(0);
+        let total = prefix_count as f64 + corrections.iter().map(Correction::weight).sum::<f64>();
+
+        let typed_probability = prefix_count as f64 / total;
+
+        let mut char_completions: Vec<Suggestion> = exact_completions.into_iter()
+            .map(|completion| completed_suggestion(completion.word.clone(), completion.probability() * typed_probability, 0))
+            .chain(corrections.into_iter().map(|correction| {
+                let score = correction.weight() / total;
+                completed_suggestion(correction.word, score, correction.edit_distance)
+            }))
+            .collect();
+
+        char_completions.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+        println!("ranked char completions: {:?}", words(&char_completions));
 
         let mut completions = predicted_completions;
         completions.extend_from_slice(&char_completions);
-        println!("all completions: {:?}", completions);
 
-        let mut predicted_previous = previous_words.clone();
-        predicted_previous.pop();
-        predicted_previous.push(completions[0].clone()); // TODO predict for multiple top candidates!
-        let predictions: Vec<String> = predict(&predicted_previous);
-        println!("char-completed predictions: {:?}", predictions);
+        // a word may be both predicted and completed, with different scores
+        let completions = best_per_word(completions);
+        println!("all completions: {:?}", words(&completions));
+
+        let completions = rescored(completions, |word| fit(completed_previous, word, &next_words), &next_words);
+
+        // predict the next word after each of the most likely completions, weighted by how likely the completion is
+        let candidates = distinct(&completions, PREDICTED_CANDIDATES);
+        let candidates_score: f64 = candidates.iter().map(|candidate| candidate.score).sum();
+        let mut predictions: Vec<Suggestion> = Vec::new();
+        let mut phrases: Vec<PhraseSuggestion> = Vec::new();
+
+        for candidate in &candidates {
+            let weight = if candidates_score > 0.0 { candidate.score / candidates_score } else { 1.0 / candidates.len() as f64 };
 
-        let response = Response { completions: completions, predictions: predictions };
-        response
+            let mut predicted_previous = completed_previous.to_vec();
+            predicted_previous.push(candidate.word.clone());
+
+            let candidate_predictions: Vec<Suggestion> = predict(&predicted_previous)
+                .into_iter().map(|prediction| predicted_suggestion(prediction, 0, 1.0)).collect();
+
+            let candidate_predictions = rescored(candidate_predictions, |word| fit(&predicted_previous, word, &next_words), &next_words);
+            println!("predictions after {:?}: {:?}", candidate.word, words(&candidate_predictions));
+
+            predictions.extend(candidate_predictions.into_iter()
+                .map(|prediction| Suggestion { score: prediction.score * weight, .. prediction }));
+
+            let candidate_phrases = rescored_phrases(predict_phrases(&predicted_previous), |phrase| {
+                let (last, first) = phrase.split_last().unwrap();
+                fit(&[ &predicted_previous[..], first ].concat(), last, &next_words)
+            }, &next_words);
+
+            phrases.extend(candidate_phrases.into_iter()
+                .map(|phrase| PhraseSuggestion { score: phrase.score * weight, .. phrase }));
+        }
+
+        let mut predictions = merged(predictions);
+        predictions.truncate(count);
+        println!("char-completed predictions: {:?}", words(&predictions));
+
+        let mut phrases = merged_phrases(phrases);
+        phrases.truncate(count);
+        println!("phrases: {:?}", phrases.iter().map(|phrase| phrase.words.join(" ")).collect::<Vec<_>>());
+
+        // the completed word is written like the typed prefix, the predicted word starts a sentence after final punctuation
+        let text = request.previous.trim_end();
+        let typed = text.rsplit(char::is_whitespace).next().unwrap_or("");
+        let completions_start_sentence = sentences::is_sentence_start(&text[.. text.len() - typed.len()]);
+        let predictions_start_sentence = sentences::is_sentence_start(text);
+
+        let phrases = phrases.into_iter().map(|phrase| PhraseSuggestion {
+            words: phrase.words.iter().enumerate()
+                .map(|(index, word)| cased(&form(word), "", index == 0 && predictions_start_sentence))
+                .collect(),
+
+            .. phrase
+        }).collect();
+
+        let cased = |suggestions: Vec<Suggestion>, typed: &str, sentence_start: bool| suggestions.into_iter()
+            .map(|suggestion| Suggestion { word: cased(&form(&suggestion.word), typed, sentence_start), .. suggestion })
+            .collect();
+
+        Response {
+            completions: cased(completions, typed, completions_start_sentence),
+            predictions: cased(predictions, "", predictions_start_sentence),
+            phrases,
+            language: language.clone(),
+        }
     };
 
     respond
-}
\ No newline at end of file
+}
+
+/// How many of the best completions the next word is predicted for.
+const PREDICTED_CANDIDATES: usize = 3;
+
+/// A completion of the typed prefix, or of a prefix that is `edit_distance` edits away from it.
+fn completed_suggestion(word: String, score: f64, edit_distance: usize) -> Suggestion {
+    Suggestion {
+        score,
+        word,
+        source: if edit_distance == 0 { SuggestionSource::PrefixCompletion } else { SuggestionSource::Correction },
+        order: None,
+        edit_distance: if edit_distance == 0 { None } else { Some(edit_distance) },
+    }
+}
+
+/// Score a predicted word, which might start with a prefix that is `edit_distance` edits away from the typed prefix,
+/// which would have been typed with that likelihood.
+fn predicted_suggestion(prediction: Prediction, edit_distance: usize, likelihood: f64) -> Suggestion {
+    Suggestion {
+        score: prediction.probability * likelihood,
+        source: if prediction.order == 0 { SuggestionSource::SentenceStarter } else { SuggestionSource::Ngram },
+        order: if prediction.order == 0 { None } else { Some(prediction.order) },
+        edit_distance: if edit_distance == 0 { None } else { Some(edit_distance) },
+        word: prediction.word,
+    }
+}
+
+/// Multiply the scores of the suggestions by how well they lead into the words after the cursor,
+/// relative to the best fitting suggestion, and sort them by the new score.
+/// Without words after the cursor, the suggestions are returned unchanged.
+fn rescored(suggestions: Vec<Suggestion>, fit: impl Fn(&str) -> f64, next_words: &[String]) -> Vec<Suggestion> {
+    if next_words.is_empty() || suggestions.is_empty() { return suggestions; }
+
+    let fits: Vec<f64> = suggestions.iter().map(|suggestion| fit(&suggestion.word)).collect();
+    let best = fits.iter().cloned().fold(0.0, f64::max);
+    if best <= 0.0 { return suggestions; }
+
+    let mut suggestions: Vec<Suggestion> = suggestions.into_iter().zip(fits)
+        .map(|(suggestion, fit)| Suggestion { score: suggestion.score * fit / best, .. suggestion })
+        .collect();
+
+    suggestions.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    println!("rescored by the following words: {:?}", words(&suggestions));
+    suggestions
+}
+
+/// Like `rescored`, multiply the scores of the phrases by how well they lead into the words after the cursor, and sort them.
+fn rescored_phrases(phrases: Vec<Phrase>, fit: impl Fn(&[String]) -> f64, next_words: &[String]) -> Vec<PhraseSuggestion> {
+    let fits: Vec<f64> = phrases.iter().map(|phrase| if next_words.is_empty() { 1.0 } else { fit(&phrase.words) }).collect();
+    let best = fits.iter().cloned().fold(0.0, f64::max);
+
+    let mut phrases: Vec<PhraseSuggestion> = phrases.into_iter().zip(fits)
+        .map(|(phrase, fit)| PhraseSuggestion {
+            score: if best > 0.0 { phrase.probability * fit / best } else { phrase.probability },
+            words: phrase.words,
+        })
+        .collect();
+
+    phrases.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    phrases
+}
+
+/// Add up the scores of identical phrases, and sort them by score.
+fn merged_phrases(phrases: Vec<PhraseSuggestion>) -> Vec<PhraseSuggestion> {
+    let mut merged: Vec<PhraseSuggestion> = Vec::new();
+
+    for phrase in phrases {
+        match merged.iter_mut().find(|other| other.words == phrase.words) {
+            Some(other) => other.score += phrase.score,
+            None => merged.push(phrase),
+        }
+    }
+
+    merged.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    merged
+}
+
+/// The first suggestions with distinct words, at most `count`.
+fn distinct(suggestions: &[Suggestion], count: usize) -> Vec<Suggestion> {
+    let mut distinct: Vec<Suggestion> = Vec::new();
+
+    for suggestion in suggestions {
+        if distinct.len() == count { break; }
+        if !distinct.iter().any(|other| other.word == suggestion.word) { distinct.push(suggestion.clone()); }
+    }
+
+    distinct
+}
+
+/// Keep the suggestion with the best score of each word, and sort them by score.
+fn best_per_word(mut suggestions: Vec<Suggestion>) -> Vec<Suggestion> {
+    suggestions.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    distinct(&suggestions, suggestions.len())
+}
+
+/// Add up the scores of suggestions of the same word, keeping the first suggestion of each word, and sort them by score.
+fn merged(suggestions: Vec<Suggestion>) -> Vec<Suggestion> {
+    let mut merged: Vec<Suggestion> = Vec::new();
+
+    for suggestion in suggestions {
+        match merged.iter_mut().find(|other| other.word == suggestion.word) {
+            Some(other) => other.score += suggestion.score,
+            None => merged.push(suggestion),
+        }
+    }
+
+    merged.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    merged
+}
+
+/// Write the word in capitals if the typed prefix is, capitalized if the prefix or the sentence starts with it,
+/// and otherwise in its most common form.
+fn cased(form: &str, typed: &str, sentence_start: bool) -> String {
+    let letters: Vec<char> = typed.chars().filter(|char| char.is_alphabetic()).collect();
+
+    if letters.len() > 1 && letters.iter().all(|char| char.is_uppercase()) {
+        form.to_uppercase()
+    }
+    else if sentence_start || letters.first().map(|char| char.is_uppercase()).unwrap_or(false) {
+        let mut chars = form.chars();
+        chars.next().map(|first| first.to_uppercase().chain(chars).collect()).unwrap_or_default()
+    }
+    else {
+        form.to_string()
+    }
+}
+
+/// The words of the suggestions, for logging.
+fn words(suggestions: &[Suggestion]) -> Vec<&str> {
+    suggestions.iter().map(|suggestion| suggestion.word.as_str()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Options for a small corpus, which is written into a new temporary directory.
+    fn corpus_options(name: &str, text: &str) -> Options {
+        let directory = std::env::temp_dir().join(format!("text-analysis-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("corpus")).unwrap();
+        fs::write(directory.join("corpus").join("text.txt"), text).unwrap();
+
+        Options {
+            corpus: CorpusConfig::from_roots(vec![ directory.join("corpus") ], None, None, "", ErrorPolicy::Abort).unwrap(),
+            completion_cache: directory.join("completion-cache"),
+            prediction_cache: directory.join("prediction-cache"),
+            order: 2,
+            count: 7,
+            phrase_length: 3,
+            beam_width: 5,
+            max_edit_distance: 2,
+            errors: Arc::new(ErrorModel::default()),
+            smoothing: Smoothing::Heuristic,
+            pruning: Pruning::default(),
+            language_threshold: 0.8,
+            fallback_language: String::new(),
+            tokenizer: Arc::new(UnicodeTokenizer::default()),
+        }
+    }
+
+    /// Sentences where "cat" and "car" follow "the", and are followed by different words.
+    /// The most common words are never predicted, so common filler words are added.
+    fn text() -> String {
+        let mut text = String::new();
+        for _ in 0 .. 10 { text.push_str("The cat sat on a mat. The car drove down a road. "); }
+        for _ in 0 .. 30 { text.push_str("One two three four five six seven. "); }
+        text
+    }
+
+    /// Random sentences of a few words, such that the n-grams occur with all kinds of counts.
+    fn random_text(seed: u64, sentences: usize) -> String {
+        let words = [ "the", "cat", "dog", "sat", "ran", "on", "a", "mat", "road", "and", "then", "slept" ];
+        let mut state = seed;
+        let mut text = String::new();
+
+        for _ in 0 .. sentences {
+            for index in 0 .. 6 {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                if index > 0 { text.push(' '); }
+                text.push_str(words[(state >> 33) as usize % words.len()]);
+            }
+
+            text.push_str(". ");
+        }
+
+        text
+    }
+
+    fn request(previous: &str) -> Request {
+        Request { previous: previous.to_string(), next: String::new(), language: None }
+    }
+
+    fn predicted(response: &Response) -> Vec<&str> {
+        response.predictions.iter().map(|suggestion| suggestion.word.as_str()).collect()
+    }
+
+    #[test]
+    fn empty_input_gives_empty_response() {
+        let respond = responder(&corpus_options("empty", &text())).unwrap();
+
+        for previous in &[ "", "   ", "...", "\n" ] {
+            let response = respond(request(previous));
+            assert!(response.completions.is_empty());
+            assert!(response.predictions.is_empty());
+        }
+    }
+
+    #[test]
+    fn unknown_word_gives_empty_response() {
+        let respond = responder(&corpus_options("unknown", &text())).unwrap();
+
+        for previous in &[ "the qqqqq", "zzz", "the xüö", "über" ] {
+            let response = respond(request(previous));
+            assert!(response.completions.is_empty());
+            assert!(response.predictions.is_empty());
+        }
+    }
+
+    #[test]
+    fn predicts_after_multiple_completions() {
+        let respond = responder(&corpus_options("candidates", &text())).unwrap();
+        let response = respond(request("the ca"));
+
+        let completions: Vec<&str> = response.completions.iter().map(|suggestion| suggestion.word.as_str()).collect();
+        assert!(completions.contains(&"cat") && completions.contains(&"car"));
+
+        let predictions = predicted(&response);
+        assert!(predictions.contains(&"sat") && predictions.contains(&"drove"));
+
+        let scores: Vec<f64> = response.predictions.iter().map(|suggestion| suggestion.score).collect();
+        assert!(scores.windows(2).all(|pair| pair[0] >= pair[1]));
+    }
+
+    #[test]
+    fn completes_each_word_once() {
+        let respond = responder(&corpus_options("once", &text())).unwrap();
+        let response = respond(request("the ca"));
+
+        let mut completions: Vec<&str> = response.completions.iter().map(|suggestion| suggestion.word.as_str()).collect();
+        let len = completions.len();
+        completions.sort_unstable();
+        completions.dedup();
+        assert_eq!(completions.len(), len);
+    }
+
+    #[test]
+    fn completes_misspelled_prefix() {
+        let respond = responder(&corpus_options("fuzzy", &text())).unwrap();
+        let response = respond(request("six sebe"));
+
+        let seven = response.completions.iter().find(|suggestion| suggestion.word == "seven").unwrap();
+        assert_eq!(seven.edit_distance, Some(1));
+    }
+
+    #[test]
+    fn kneser_ney_sums_to_one() {
+        for order in 1 ..= 3 {
+            let mut options = corpus_options(&format!("kneser-ney-{}", order), &random_text(3, 300));
+            options.order = order - 1;
+
+            let models = ModelBuilder::new(&options, "", Rebuild::IfOutdated).build().unwrap();
+            let words: Vec<String> = models.words.with_prefix("").map(|(word, _)| word.to_string()).collect();
+
+            let mut contexts: Vec<Vec<String>> = vec![ Vec::new() ];
+            contexts.extend(words.iter().map(|word| vec![ word.clone() ]));
+            contexts.extend(words.iter().map(|word| vec![ "the".to_string(), word.clone() ]));
+
+            for context in &contexts {
+                let probability = |word: &str| models.prediction.probability(word, context, Smoothing::KneserNey);
+                let sum = words.iter().map(|word| probability(word)).sum::<f64>() + probability("unknown");
+                assert!((sum - 1.0).abs() < 1e-9, "order {} after {:?} sums to {}", order, context, sum);
+            }
+        }
+    }
+
+    #[test]
+    fn appending_predicts_like_building() {
+        let (first, second) = (random_text(1, 200), random_text(2, 200));
+
+        let appended = corpus_options("appended", &first);
+        let rebuilt = corpus_options("rebuilt", &first);
+        ModelBuilder::new(&appended, "", Rebuild::IfOutdated).build().unwrap();
+
+        for options in &[ &appended, &rebuilt ] {
+            let more = options.corpus.sources[0].root.join("more");
+            fs::create_dir_all(&more).unwrap();
+            fs::write(more.join("text.txt"), &second).unwrap();
+        }
+
+        let more = appended.corpus.sources[0].root.join("more");
+        prediction::append(&appended.corpus, &appended.prediction_cache, appended.order, &appended.pruning, &*appended.tokenizer, &more).unwrap();
+
+        let (model, _) = prediction::load(
+            &appended.corpus, &appended.prediction_cache, appended.order, &appended.pruning, &*appended.tokenizer, Rebuild::IfOutdated
+        ).unwrap();
+
+        let appended = model.unwrap();
+        let rebuilt = ModelBuilder::new(&rebuilt, "", Rebuild::IfOutdated).build().unwrap();
+
+        let words: Vec<String> = rebuilt.words.with_prefix("").map(|(word, _)| word.to_string()).collect();
+        let mut contexts: Vec<Vec<String>> = vec![ Vec::new() ];
+        contexts.extend(words.iter().map(|word| vec![ word.clone() ]));
+        contexts.extend(words.iter().flat_map(|first| words.iter().map(move |second| vec![ first.clone(), second.clone() ])));
+
+        for context in &contexts {
+            for word in &words {
+                for &smoothing in &[ Smoothing::Heuristic, Smoothing::KneserNey ] {
+                    let expected = rebuilt.prediction.probability(word, context, smoothing);
+                    let actual = appended.probability(word, context, smoothing);
+                    assert!((expected - actual).abs() < 1e-12, "{} after {:?}: {} instead of {}", word, context, actual, expected);
+                }
+            }
+        }
+    }
+}
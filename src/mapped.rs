@@ -0,0 +1,297 @@
+//! The payloads of the cache files, which are read in place instead of being deserialized.
+//!
+//! A payload consists of sections, each of which is a plain little-endian array or a byte string:
+//! 1. The number of sections, a `u32`.
+//! 2. The end offset of each section, a `u64`, relative to the first byte after the offsets.
+//! 3. The contents of all sections, one after another.
+//!
+//! On unix, the cache file is memory-mapped, such that only the pages that are actually
+//! queried are read, and multiple processes share the same pages of the page cache.
+
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::path::Path;
+use std::fs::File;
+use std::io;
+
+/// The bytes of a file, mapped into memory, or just bytes in memory.
+pub struct Bytes {
+    source: Source,
+    start: usize,
+}
+
+enum Source {
+    #[cfg(unix)]
+    Mapped { pointer: *mut libc::c_void, len: usize },
+    Owned(Vec<u8>),
+}
+
+// the mapping is read-only and never changes
+unsafe impl Send for Source {}
+unsafe impl Sync for Source {}
+
+impl Bytes {
+
+    /// Map the whole file into memory.
+    #[cfg(unix)]
+    pub fn map(path: &Path) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 { return Ok(Bytes::from(Vec::new())); }
+
+        let pointer = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+
+        if pointer == libc::MAP_FAILED { return Err(io::Error::last_os_error()); }
+        Ok(Bytes { source: Source::Mapped { pointer, len }, start: 0 })
+    }
+
+    /// Read the whole file into memory, as memory maps are only used on unix.
+    #[cfg(not(unix))]
+    pub fn map(path: &Path) -> io::Result<Self> {
+        use std::io::Read;
+
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Ok(Bytes::from(bytes))
+    }
+
+    /// The same bytes, without the first `count` bytes.
+    pub fn skip(self, count: usize) -> Self {
+        Bytes { start: self.start + count, .. self }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        let all = match &self.source {
+            #[cfg(unix)]
+            Source::Mapped { pointer, len } => unsafe { std::slice::from_raw_parts(*pointer as *const u8, *len) },
+            Source::Owned(bytes) => bytes.as_slice(),
+        };
+
+        &all[self.start ..]
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytes { source: Source::Owned(bytes), start: 0 }
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        #[cfg(unix)] {
+            if let Source::Mapped { pointer, len } = *self {
+                unsafe { libc::munmap(pointer, len); }
+            }
+        }
+    }
+}
+
+/// A payload, along with the location of its sections.
+pub struct Sections {
+    bytes: Bytes,
+    ranges: Vec<Range<usize>>,
+}
+
+impl Sections {
+
+    /// Read the section offsets, and check that all sections are within the payload.
+    pub fn read(bytes: Bytes, expected_count: usize) -> Result<Self, String> {
+        let truncated = || "truncated payload".to_string();
+        let payload = bytes.as_slice();
+
+        let count = Array::<u32>::new(payload.get(.. 4).ok_or_else(truncated)?).get(0) as usize;
+
+        if count != expected_count {
+            return Err(format!("contains {} sections instead of {}", count, expected_count));
+        }
+
+        let offsets = Array::<u64>::new(payload.get(4 .. 4 + count * 8).ok_or_else(truncated)?);
+        let data_start = 4 + count * 8;
+
+        let mut ranges = Vec::with_capacity(count);
+        let mut start = data_start;
+
+        for index in 0 .. count {
+            let end = data_start + offsets.get(index) as usize;
+            if end < start || end > payload.len() { return Err(truncated()); }
+
+            ranges.push(start .. end);
+            start = end;
+        }
+
+        Ok(Sections { bytes, ranges })
+    }
+
+    /// The whole payload, including the section offsets.
+    pub fn payload(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+
+    pub fn bytes(&self, section: usize) -> &[u8] {
+        &self.bytes.as_slice()[self.ranges[section].clone()]
+    }
+
+    pub fn array<T: Scalar>(&self, section: usize) -> Array<'_, T> {
+        Array::new(self.bytes(section))
+    }
+
+    pub fn strings(&self, offsets: usize, bytes: usize) -> Strings<'_> {
+        Strings { offsets: self.array(offsets), bytes: self.bytes(bytes) }
+    }
+}
+
+/// Collects the sections of a new payload.
+#[derive(Default)]
+pub struct Writer {
+    sections: Vec<Vec<u8>>,
+}
+
+impl Writer {
+    pub fn array<T: Scalar>(&mut self, values: impl IntoIterator<Item = T>) {
+        let mut bytes = Vec::new();
+        for value in values { value.write(&mut bytes); }
+        self.sections.push(bytes);
+    }
+
+    pub fn bytes(&mut self, bytes: Vec<u8>) {
+        self.sections.push(bytes);
+    }
+
+    /// Add the strings as two sections, the end offset of each string, and the concatenated strings.
+    pub fn strings<'s>(&mut self, strings: impl IntoIterator<Item = &'s str>) {
+        let mut bytes = Vec::new();
+        let mut offsets = vec![ 0_u32 ];
+
+        for string in strings {
+            bytes.extend_from_slice(string.as_bytes());
+            offsets.push(bytes.len() as u32);
+        }
+
+        self.array(offsets);
+        self.bytes(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let data_len: usize = self.sections.iter().map(Vec::len).sum();
+        let mut payload = Vec::with_capacity(4 + self.sections.len() * 8 + data_len);
+
+        (self.sections.len() as u32).write(&mut payload);
+
+        let mut end = 0_u64;
+        for section in &self.sections {
+            end += section.len() as u64;
+            end.write(&mut payload);
+        }
+
+        for section in self.sections {
+            payload.extend_from_slice(&section);
+        }
+
+        payload
+    }
+}
+
+/// A number that is stored in little-endian byte order.
+pub trait Scalar: Copy + 'static {
+    const SIZE: usize;
+    fn read(bytes: &[u8]) -> Self;
+    fn write(self, bytes: &mut Vec<u8>);
+}
+
+impl Scalar for u32 {
+    const SIZE: usize = 4;
+    fn read(bytes: &[u8]) -> Self { u32::from_le_bytes(bytes.try_into().unwrap()) }
+    fn write(self, bytes: &mut Vec<u8>) { bytes.extend_from_slice(&self.to_le_bytes()) }
+}
+
+impl Scalar for u64 {
+    const SIZE: usize = 8;
+    fn read(bytes: &[u8]) -> Self { u64::from_le_bytes(bytes.try_into().unwrap()) }
+    fn write(self, bytes: &mut Vec<u8>) { bytes.extend_from_slice(&self.to_le_bytes()) }
+}
+
+impl Scalar for f64 {
+    const SIZE: usize = 8;
+    fn read(bytes: &[u8]) -> Self { f64::from_bits(u64::read(bytes)) }
+    fn write(self, bytes: &mut Vec<u8>) { self.to_bits().write(bytes) }
+}
+
+/// An array of numbers, read in place. The bytes do not need to be aligned.
+#[derive(Clone, Copy)]
+pub struct Array<'b, T> {
+    bytes: &'b [u8],
+    scalar: PhantomData<T>,
+}
+
+impl<'b, T: Scalar> Array<'b, T> {
+    pub fn new(bytes: &'b [u8]) -> Self {
+        Array { bytes, scalar: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len() / T::SIZE
+    }
+
+    pub fn get(&self, index: usize) -> T {
+        T::read(&self.bytes[index * T::SIZE .. (index + 1) * T::SIZE])
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        Array::new(&self.bytes[range.start * T::SIZE .. range.end * T::SIZE])
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = T> + 'b {
+        self.bytes.chunks_exact(T::SIZE).map(T::read)
+    }
+
+    /// The index of the first element that is not less than the searched value, assuming a sorted array.
+    pub fn partition_point(&self, is_less: impl Fn(T) -> bool) -> usize {
+        partition_point(self.len(), |index| is_less(self.get(index)))
+    }
+}
+
+/// A list of strings, read in place.
+#[derive(Clone, Copy)]
+pub struct Strings<'b> {
+    offsets: Array<'b, u32>,
+    bytes: &'b [u8],
+}
+
+impl<'b> Strings<'b> {
+    pub fn len(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+
+    pub fn get(&self, index: usize) -> &'b str {
+        // corrupted offsets or bytes result in an empty string, as the checksum is not always verified
+        self.bytes.get(self.offsets.get(index) as usize .. self.offsets.get(index + 1) as usize)
+            .and_then(|bytes| std::str::from_utf8(bytes).ok()).unwrap_or("")
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = &'b str> {
+        (0 .. self.len()).map(move |index| self.get(index))
+    }
+
+    /// The index of the first string that is not less than the searched string, assuming sorted strings.
+    pub fn partition_point(&self, is_less: impl Fn(&str) -> bool) -> usize {
+        partition_point(self.len(), |index| is_less(self.get(index)))
+    }
+}
+
+/// Binary search for the first index of the sorted range at which `is_less` becomes false.
+pub fn partition_point(len: usize, is_less: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+
+    while low < high {
+        let middle = (low + high) / 2;
+        if is_less(middle) { low = middle + 1; } else { high = middle; }
+    }
+
+    low
+}
@@ -7,139 +7,1223 @@
 //
 
 
-use crate::corpus::split_to_words;
-use std::collections::{HashMap, BTreeMap};
-use rayon::prelude::IntoParallelIterator;
+use crate::tokenizer::{Tokenizer, UnicodeTokenizer};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use std::fs::File;
-use crate::corpus;
+use crate::corpus::{self, CorpusConfig, ErrorPolicy, Manifest, ManifestEntry};
+use crate::error::{Error, Result};
+use crate::cache::{self, Contents, Rebuild};
+use crate::mapped::{Array, Bytes, Sections, Strings, Writer};
+use string_interner::StringInterner;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::sync::Arc;
 
-const MAX_CHAIN_LEN: usize = 2;
+type StringId = usize;
+type Count<T> = HashMap<T, usize>;
+type Chains = HashMap<Context, Count<StringId>>;
 
-pub fn ngram_predictor() -> impl (Fn(&[String]) -> Vec<String>) {
-    let path = Path::new(".prediction-cache");
-    type StringId = usize;
+/// The number of most common words that are never predicted, as they would always be predicted.
+const TOP_WORD_COUNT: usize = 7;
 
-    let (starters, strings, chains, top_words) = {
-        println!("attempting to load prediction cache...");
-        let cache_result = File::open(path).ok().and_then(|file| bincode::deserialize_from(file).ok());
+/// The maximum number of previous words that a prediction is based on.
+pub const MAX_CHAIN_LEN: usize = 5;
 
-        if let Some(result) = cache_result {
-            println!("... loaded cache");
-            result
+/// The number of bits of each word id in a packed `Context`.
+const CONTEXT_ID_BITS: u32 = 24;
+
+/// The number of distinct words whose ids fit into a packed `Context`.
+const MAX_WORD_COUNT: usize = 1 << CONTEXT_ID_BITS;
+
+/// How many of the most common successors of each context are scored, per requested prediction.
+const CANDIDATES_PER_PREDICTION: usize = 4;
+
+// The sections of the cache file. The strings are stored in the order of their ids,
+// and the successor lists of all contexts are stored one after another.
+const STRING_OFFSETS: usize = 0;
+const STRING_BYTES: usize = 1;
+const SORTED_STRINGS: usize = 2; // `u32` ids, sorted by their string
+const TOP_WORDS: usize = 3; // `u32` ids
+const DISCOUNTS: usize = 4; // three `f64` for each context length
+const SUCCESSOR_WORDS: usize = 5; // `u32` ids
+const SUCCESSOR_COUNTS: usize = 6; // `u64`
+const SUCCESSOR_CONTINUATIONS: usize = 7; // `u64`
+const SUCCESSOR_INDEX: usize = 8; // `u32` positions within each list, sorted by the word id
+const STARTERS: usize = 9; // the `LIST_FIELDS` of the sentence starters
+const CONTEXTS: usize = 10; // for each context length, the sorted `u32` keys, followed by their `LIST_FIELDS`
+
+/// The start, length, total, continuation total, the three classes and the three continuation classes of a list.
+const LIST_FIELDS: usize = 10;
+
+/// The bytes of a single successor in the `SUCCESSOR_*` sections.
+const SUCCESSOR_SIZE: usize = 4 + 8 + 8 + 4;
+
+/// The number of sections of a prediction cache.
+pub fn section_count(max_chain_len: usize) -> usize {
+    CONTEXTS + 2 * (max_chain_len + 1)
+}
+
+/// How the probability of a word after the previous words is estimated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Smoothing {
+
+    /// The relative frequency after the longest context in which the word was observed.
+    /// Predicts all successors of the longest context first. This is not a normalized distribution.
+    Heuristic,
+
+    /// Interpolated modified Kneser-Ney smoothing, which combines the discounted counts of all context lengths.
+    KneserNey,
+}
+
+/// Which n-grams are removed from the prediction model before it is written to the cache.
+/// The rules are applied in the order of the fields. The sentence starters are never pruned.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Pruning {
+
+    /// The minimum count of the n-grams of each order, starting with single words.
+    /// The last value applies to all higher orders.
+    pub min_counts: Vec<usize>,
+
+    /// Remove the contexts of more than one word that have a single successor,
+    /// as the shorter context usually predicts that successor as well.
+    pub single_successors: bool,
+
+    /// How many of the most common successors of each context of at least one word are kept.
+    pub top_successors: Option<usize>,
+
+    /// Remove the n-grams whose removal changes the model less than this threshold,
+    /// estimated by the weighted difference of their relative frequency and the frequency after the shorter context.
+    pub entropy_threshold: Option<f64>,
+
+    /// Remove the least common n-grams of the longest contexts first, until the payload of the cache fits into this many bytes.
+    pub target_size: Option<usize>,
+}
+
+impl Pruning {
+
+    /// The minimum count of the n-grams of the order, which is one if no minimum was given.
+    fn min_count(&self, order: usize) -> usize {
+        self.min_counts.get(order - 1).or_else(|| self.min_counts.last()).cloned().unwrap_or(1)
+    }
+
+    /// Whether none of the rules removes any n-grams.
+    fn is_empty(&self) -> bool {
+        self.min_counts.iter().all(|&count| count <= 1) && !self.single_successors
+            && self.top_successors.is_none() && self.entropy_threshold.is_none() && self.target_size.is_none()
+    }
+}
+
+/// How many n-grams a pruning rule removed, and how many contexts were left without any successors.
+#[derive(Debug, Default)]
+struct Removed {
+    ngrams: usize,
+    contexts: usize,
+}
+
+/// The word chains of the corpus, along with the number of occurrences of each successor.
+/// Used for counting the corpus, and written to the cache as a `MappedModel`.
+pub struct Model {
+    max_chain_len: usize,
+    strings: StringInterner<StringId>,
+
+    /// The words that start a sentence.
+    starters: Successors,
+
+    /// The successors of each chain of previous words.
+    /// The empty chain is followed by every word of the corpus.
+    chains: HashMap<Context, Successors>,
+
+    top_words: Vec<StringId>,
+
+    /// The discounts of the counts before pruning, which are computed from the current counts if empty.
+    discounts: Vec<[f64; 3]>,
+
+    /// The contexts that have been pruned and have not changed since, which are not pruned again.
+    pruned: HashSet<Context>,
+}
+
+/// The word chains of a cache file, which are queried in place.
+pub struct MappedModel {
+    max_chain_len: usize,
+    sections: Sections,
+}
+
+/// A chain of up to `MAX_CHAIN_LEN` word ids, packed into a single integer instead of a heap-allocated vector.
+/// Each id occupies 24 bits, with the last word in the lowest bits, and the top byte holds the number of words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Context(u128);
+
+impl Context {
+    fn new(words: &[StringId]) -> Self {
+        assert!(words.len() <= MAX_CHAIN_LEN, "context of {} words is too long", words.len());
+
+        let ids = words.iter().fold(0_u128, |packed, &id| {
+            debug_assert!(id < MAX_WORD_COUNT, "too many distinct words for a packed context");
+            (packed << CONTEXT_ID_BITS) | id as u128
+        });
+
+        Context(((words.len() as u128) << 120) | ids)
+    }
+
+    fn len(self) -> usize {
+        (self.0 >> 120) as usize
+    }
+
+    fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+
+    fn words(self) -> Vec<StringId> {
+        let mask = (1_u128 << CONTEXT_ID_BITS) - 1;
+
+        (0 .. self.len()).rev()
+            .map(|index| ((self.0 >> (CONTEXT_ID_BITS * index as u32)) & mask) as StringId)
+            .collect()
+    }
+
+    /// The same context without its first word.
+    fn shorter(self) -> Self {
+        let len = self.len() - 1;
+        let ids = self.0 & ((1_u128 << (CONTEXT_ID_BITS * len as u32)) - 1);
+        Context(((len as u128) << 120) | ids)
+    }
+}
+
+/// The sentences of a part of the corpus, counted by a single worker, which assigns its own string ids.
+pub struct Counts {
+    strings: StringInterner<StringId>,
+    starters: Count<StringId>,
+    chains: Chains,
+    word_count: u128,
+    char_count: u128,
+
+    /// Whether the ids of some words do not fit into a context, such that only single words were counted since then.
+    too_many_words: bool,
+}
+
+impl Counts {
+    pub fn new() -> Self {
+        Counts {
+            strings: StringInterner::new(),
+            starters: HashMap::new(),
+            chains: HashMap::new(),
+            word_count: 0,
+            char_count: 0,
+            too_many_words: false,
         }
-        else {
-            println!("... invalid, computing new prediction cache");
-            type Count<T> = HashMap<T, usize>;
-            type Chain<T> = HashMap<Vec<T>, Count<T>>;
-            use string_interner::StringInterner;
-
-            let mut strings: StringInterner<StringId> = string_interner::StringInterner::with_capacity(2048);
-
-            // initialize the statistical data which we are going fill by analyzing the corpus
-            let mut word_chains: Chain<StringId> = Chain::with_capacity(1024*1024);
-            let mut all_chars: Count<char> = HashMap::new();
-            let mut sentence_starters : Count<StringId> = HashMap::new();
-            let mut all_words : Count<StringId> = HashMap::new();
-            let mut word_count: u128 = 0;
-            let mut char_count: u128 = 0;
-
-            for string in corpus::sentences() {
-                let sentence = split_to_words(&string);
-                if sentence.is_empty() { continue; }
-
-                let words: Vec<StringId> = sentence.iter().map(|string| strings.get_or_intern(string)).collect();
-
-                for &word in &words {
-                    *all_words.entry(word).or_insert(0) += 1;
-                }
+    }
 
-                *sentence_starters.entry(*words.first().unwrap()).or_insert(0) += 1;
+    /// Count the words of the sentence, and all chains of up to `max_chain_len` words followed by another word.
+    pub fn add(&mut self, max_chain_len: usize, weight: usize, string: &str, sentence: &[String]) {
+        if sentence.is_empty() { return; }
 
-                word_count += sentence.len() as u128;
+        let strings = &mut self.strings;
+        let words: Vec<StringId> = sentence.iter().map(|string| strings.get_or_intern(string)).collect();
+        *self.starters.entry(words[0]).or_insert(0) += weight;
 
-                for char in string.chars() {
-                    *all_chars.entry(char).or_insert(0) += 1;
-                    char_count += 1;
-                }
+        self.word_count += sentence.len() as u128;
+        self.char_count += string.chars().count() as u128;
 
-                for chain_len in 1 ..=MAX_CHAIN_LEN {
-                    for key in words.windows(chain_len + 1) {
-                        let value = &key[chain_len];
-                        let key = Vec::from(&key[ .. chain_len]);
+        self.too_many_words |= strings.len() > MAX_WORD_COUNT;
+        let max_chain_len = if self.too_many_words { 0 } else { max_chain_len };
 
-                        let map = word_chains.entry(key).or_insert_with(HashMap::new);
-                        *map.entry(*value).or_insert(0) += 1;
-                    }
-                }
+        // the chain of length zero counts every word
+        for chain_len in 0 ..= max_chain_len {
+            for key in words.windows(chain_len + 1) {
+                let value = &key[chain_len];
+                let key = Context::new(&key[ .. chain_len]);
+
+                let map = self.chains.entry(key).or_default();
+                *map.entry(*value).or_insert(0) += weight;
             }
+        }
+    }
+
+    /// Add the counts of the later files to the counts of the earlier files.
+    pub fn merge(mut self, later: Counts) -> Counts {
+        if self.strings.is_empty() { return later; }
+
+        self.word_count += later.word_count;
+        self.char_count += later.char_count;
+        self.too_many_words |= later.too_many_words;
+
+        let (starters, chains) = later.translate(&mut self.strings);
+        self.too_many_words |= self.strings.len() > MAX_WORD_COUNT;
+
+        add_counts(&mut self.starters, starters);
+
+        for (key, successors) in chains {
+            add_counts(self.chains.entry(key).or_default(), successors);
+        }
+
+        self
+    }
+
+    /// All counted words, along with their number of occurrences.
+    pub fn words(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.chains.get(&Context::new(&[])).into_iter().flat_map(move |words| {
+            words.iter().map(move |(&id, &count)| (self.strings.resolve(id).unwrap(), count))
+        })
+    }
+
+    /// Replace the string ids of this worker with the ids of the strings, which are extended by the new words.
+    /// The new words are added in the order of their first occurrence.
+    /// Chains with words whose new ids do not fit into a context are dropped.
+    fn translate(self, strings: &mut StringInterner<StringId>) -> (Count<StringId>, Chains) {
+        let mut ids = vec![0; self.strings.len()];
+
+        for (id, string) in self.strings.iter() {
+            ids[id] = strings.get_or_intern(string);
+        }
+
+        let translate = |counts: Count<StringId>| -> Count<StringId> {
+            counts.into_iter().map(|(id, count)| (ids[id], count)).collect()
+        };
+
+        let chains = self.chains.into_iter()
+            .filter(|(key, _)| key.words().into_iter().all(|id| ids[id] < MAX_WORD_COUNT))
+            .map(|(key, successors)| {
+                let key: Vec<StringId> = key.words().into_iter().map(|id| ids[id]).collect();
+                (Context::new(&key), translate(successors))
+            })
+            .collect();
+
+        (translate(self.starters), chains)
+    }
+}
+
+fn add_counts(counts: &mut Count<StringId>, more: Count<StringId>) {
+    for (id, count) in more {
+        *counts.entry(id).or_insert(0) += count;
+    }
+}
+
+/// The words following a context, most common first.
+#[derive(Default)]
+struct Successors {
+
+    /// The sum of all counts, which is the number of occurrences of the context.
+    total: usize,
+
+    /// The sum of all continuation counts.
+    continuation_total: usize,
+
+    /// How many successors have a count of one, two, and three or more.
+    classes: [usize; 3],
+
+    /// How many successors have a continuation count of one, two, and three or more.
+    continuation_classes: [usize; 3],
+
+    words: Vec<Successor>,
+}
 
-            println!("analyzed all files");
-            println!("processed {} words", word_count);
-            println!("processed {} chars", char_count);
-            println!("collected {} distinct words", strings.len());
-            println!("collected {} prediction entries", word_chains.len());
+#[derive(Debug, Clone, Copy)]
+struct Successor {
+    word: StringId,
 
+    /// How often the word followed the context.
+    count: usize,
 
-            fn map_to_sorted_count_vec<T>(map: impl Iterator<Item = (T, usize)>) -> Vec<(usize, T)> {
-                let tree: BTreeMap<usize, T> = map.map(|(value, count)| (count, value)).collect();
-                tree.into_iter().rev().collect()
+    /// After how many distinct words the context was followed by this word.
+    continuation: usize,
+}
+
+/// The successors of a context in a cache file, most common first.
+#[derive(Clone, Copy)]
+struct List<'m> {
+    sections: &'m Sections,
+    start: usize,
+    len: usize,
+    total: usize,
+    continuation_total: usize,
+    classes: [usize; 3],
+    continuation_classes: [usize; 3],
+}
+
+/// A predicted word, along with how likely it follows the previous words.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prediction {
+    pub word: String,
+
+    /// The estimated probability of the word after the previous words.
+    pub probability: f64,
+
+    /// How often the word followed the context.
+    pub count: usize,
+
+    /// The number of previous words in the longest context that the word followed, zero for sentence starters.
+    pub order: usize,
+}
+
+/// A predicted sequence of words, along with how likely it follows the previous words.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phrase {
+    pub words: Vec<String>,
+
+    /// The estimated probability of all words of the phrase, one after another, after the previous words.
+    pub probability: f64,
+}
+
+/// The perplexities of both smoothing methods on held-out text, lower is better.
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub words: usize,
+    pub unknown_words: usize,
+
+    /// The perplexity of the heuristic probabilities, normalized over all words after each context.
+    pub heuristic: f64,
+    pub kneser_ney: f64,
+}
+
+impl Model {
+    pub fn new(max_chain_len: usize) -> Self {
+        Model {
+            max_chain_len,
+            strings: StringInterner::with_capacity(2048),
+            starters: Successors::default(),
+            chains: HashMap::new(),
+            top_words: Vec::new(),
+            discounts: Vec::new(),
+            pruned: HashSet::new(),
+        }
+    }
+
+    /// Read all word chains of a cache file into memory, such that more sentences can be added.
+    /// The stored successor lists are already sorted and pruned, and the discounts were estimated before pruning.
+    fn read(sections: &Sections, max_chain_len: usize) -> Self {
+        let mut model = Model::new(max_chain_len);
+
+        for string in sections.strings(STRING_OFFSETS, STRING_BYTES).iter() {
+            model.strings.get_or_intern(string);
+        }
+
+        let successors = |list: List<'_>| Successors {
+            total: list.total,
+            continuation_total: list.continuation_total,
+            classes: list.classes,
+            continuation_classes: list.continuation_classes,
+            words: list.iter().collect(),
+        };
+
+        model.starters = successors(List::read(sections, sections.array(STARTERS), 0));
+
+        for chain_len in 0 ..= max_chain_len {
+            let keys = sections.array::<u32>(CONTEXTS + 2 * chain_len);
+            let lists = sections.array::<u64>(CONTEXTS + 2 * chain_len + 1);
+
+            for index in 0 .. lists.len() / LIST_FIELDS {
+                let key: Vec<StringId> = keys.slice(index * chain_len .. (index + 1) * chain_len).iter()
+                    .map(|id| id as StringId).collect();
+
+                model.chains.insert(Context::new(&key), successors(List::read(sections, lists, index)));
             }
+        }
+
+        model.top_words = sections.array::<u32>(TOP_WORDS).iter().map(|id| id as StringId).collect();
+
+        let discounts = sections.array::<f64>(DISCOUNTS);
+        model.discounts = (0 .. discounts.len() / 3)
+            .map(|chain_len| [ discounts.get(chain_len * 3), discounts.get(chain_len * 3 + 1), discounts.get(chain_len * 3 + 2) ])
+            .collect();
+
+        model.pruned = model.chains.keys().copied().collect();
+        model
+    }
+
+    /// Count the sentences of the files in parallel, and merge them into this model.
+    pub fn add_files(&mut self, files: &[ManifestEntry], on_error: ErrorPolicy, tokenizer: &dyn Tokenizer) -> Result<()> {
+        let max_chain_len = self.max_chain_len;
+
+        let counts = corpus::fold_sentences_of(
+            files, on_error, Counts::new,
+            |counts, weight, sentence| counts.add(max_chain_len, weight, &sentence, &tokenizer.words(&sentence)),
+            Counts::merge
+        )?;
+
+        self.add(counts)
+    }
 
-            fn map_to_sorted_vec<T>(map: Count<T>) -> Vec<T> {
-                let tree: BTreeMap<usize, T> = map.into_iter()
-                    .map(|(value, count)| (count, value)).collect();
+    /// Merge the counted sentences into this model.
+    /// Only the successor lists of chains that occur in the new sentences are sorted again, and pruned again later.
+    ///
+    /// When merging into a pruned model, the merged counts differ from counting the whole corpus at once:
+    /// pruned n-grams start counting from zero again, and as the successors of pruned contexts are unknown,
+    /// each of their successors adds to the continuation count of the shorter context again.
+    /// Fails if there are more distinct words than a context can refer to.
+    pub fn add(&mut self, counts: Counts) -> Result<()> {
+        println!("analyzed all files");
+        println!("processed {} words", counts.word_count);
+        println!("processed {} chars", counts.char_count);
 
-                tree.into_iter().map(|(_, value)| value).rev().collect()
+        let too_many_words = counts.too_many_words;
+        let (sentence_starters, word_chains) = counts.translate(&mut self.strings);
+
+        if too_many_words || self.strings.len() > MAX_WORD_COUNT {
+            return Err(Error::Vocabulary(MAX_WORD_COUNT));
+        }
+
+        println!("collected {} distinct words", self.strings.len());
+        println!("collected {} new prediction entries", word_chains.len());
+
+        // each new successor of a context means that the shorter context
+        // has been followed by that word after one more distinct word
+        // (if the longer context has been pruned before, its successors are not known and are counted again)
+        let mut continuations: Chains = HashMap::new();
+
+        for (key, successors) in &word_chains {
+            if key.is_empty() { continue; }
+
+            let known: HashSet<StringId> = self.chains.get(key)
+                .map(|known| known.words.iter().map(|successor| successor.word).collect())
+                .unwrap_or_default();
+
+            let shorter = continuations.entry(key.shorter()).or_default();
+
+            for &word in successors.keys().filter(|word| !known.contains(word)) {
+                *shorter.entry(word).or_insert(0) += 1;
             }
+        }
 
-            let words = map_to_sorted_vec(all_words);
-            let top_word_count = 7;
+        self.starters.merge(sentence_starters, HashMap::new());
+
+        for (key, successors) in word_chains {
+            let continuation = continuations.remove(&key).unwrap_or_default();
+            self.pruned.remove(&key);
+            self.chains.entry(key).or_default().merge(successors, continuation);
+        }
 
-            println!("top {} common words: {:?}", top_word_count, words[..top_word_count].iter().map(|&id| strings.resolve(id).unwrap()).collect::<Vec<_>>());
+        println!("condensed to {} prediction entries", self.chains.len());
 
-            let starters = map_to_sorted_vec(sentence_starters);
-            let starters: Vec<String> = starters.into_iter()
-                .filter(|starter| !words[..top_word_count].contains(starter))
-                .map(|id| strings.resolve(id).unwrap().to_string())
+        self.top_words = self.chains.get(&Context::new(&[])).into_iter()
+            .flat_map(|all| all.words.iter().take(TOP_WORD_COUNT))
+            .map(|successor| successor.word).collect();
+
+        println!("top {} common words: {:?}", self.top_words.len(), self.top_words.iter().map(|&id| self.strings.resolve(id).unwrap()).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    /// Remove the n-grams that the pruning rules consider unnecessary, and report how many each rule removed.
+    /// The discounts are estimated before pruning, as the pruned counts would distort them.
+    /// Contexts that have been pruned before and have not changed since are only affected by the target size.
+    ///
+    /// After merging new counts into a model that was read from a cache, the discounts are only estimated again
+    /// if the rules never remove anything, such that they are the same as if the whole corpus had been counted at once.
+    /// Otherwise the counts of the removed n-grams are unknown, and the discounts of the cache are kept.
+    pub fn prune(&mut self, pruning: &Pruning) {
+        if pruning.is_empty() { self.discounts.clear(); }
+        self.discounts = self.discounts();
+        let pruned = std::mem::take(&mut self.pruned);
+
+        if !pruning.min_counts.is_empty() {
+            let removed = self.remove_where(|key, _, successor| {
+                !pruned.contains(&key) && successor.count < pruning.min_count(key.len() + 1)
+            });
+
+            println!("minimum counts removed {} n-grams and {} contexts", removed.ngrams, removed.contexts);
+        }
+
+        if pruning.single_successors {
+            let single: HashSet<Context> = self.chains.iter()
+                .filter(|&(key, successors)| key.len() > 1 && successors.words.len() == 1 && !pruned.contains(key))
+                .map(|(&key, _)| key).collect();
+
+            let removed = self.remove_where(|key, _, _| single.contains(&key));
+            println!("single successors removed {} contexts of multiple words", removed.contexts);
+        }
+
+        if let Some(top_successors) = pruning.top_successors {
+            let removed = self.remove_where(|key, position, _| !key.is_empty() && !pruned.contains(&key) && position >= top_successors);
+            println!("top {} successors removed {} n-grams and {} contexts", top_successors, removed.ngrams, removed.contexts);
+        }
+
+        if let Some(threshold) = pruning.entropy_threshold {
+            let doomed: HashSet<(Context, StringId)> = self.entropy_costs().into_iter()
+                .filter(|&(key, _, cost)| cost < threshold && !pruned.contains(&key))
+                .map(|(key, word, _)| (key, word))
                 .collect();
 
-            let chains: HashMap<Vec<StringId>, Vec<StringId>> = word_chains
-                .into_iter().filter(|(key, values)| !values.is_empty() && (key.len() == 1 || values.len() > 1))
-                .map(|(words, successors)| (words, map_to_sorted_vec(successors)))
+            let removed = self.remove_where(|key, _, successor| doomed.contains(&(key, successor.word)));
+            println!("entropy threshold {} removed {} n-grams and {} contexts", threshold, removed.ngrams, removed.contexts);
+        }
+
+        if let Some(target_size) = pruning.target_size {
+            let mut size = self.payload_size();
+
+            // remove the least common n-grams first, and of those the ones with the longest context
+            let mut candidates: Vec<(usize, Reverse<usize>, u128, StringId)> = self.chains.iter()
+                .filter(|(key, _)| !key.is_empty())
+                .flat_map(|(key, successors)| successors.words.iter().map(move |successor| {
+                    (successor.count, Reverse(key.len()), key.0, successor.word)
+                }))
                 .collect();
 
-            println!("condensed to {} prediction entries", chains.len());
+            candidates.sort_unstable();
+
+            let mut remaining: HashMap<Context, usize> = self.chains.iter()
+                .map(|(&key, successors)| (key, successors.words.len())).collect();
+
+            let mut doomed = HashSet::new();
 
-            let top_words = words[.. top_word_count].to_vec();
+            for (_, _, key, word) in candidates {
+                if size <= target_size { break; }
 
+                let key = Context(key);
+                let remaining = remaining.get_mut(&key).unwrap();
 
-            let result = (starters, strings, chains, top_words);
-            bincode::serialize_into(File::create(path).unwrap(), &result).unwrap();
-            result
+                *remaining -= 1;
+                size -= SUCCESSOR_SIZE;
+                if *remaining == 0 { size -= context_size(key); }
+
+                doomed.insert((key, word));
+            }
+
+            let removed = self.remove_where(|key, _, successor| doomed.contains(&(key, successor.word)));
+            println!("target size of {} bytes removed {} n-grams and {} contexts", target_size, removed.ngrams, removed.contexts);
+
+            if size > target_size {
+                println!("the model still requires {} bytes, as words and sentence starters are never removed", size);
+            }
         }
-    };
 
-    move |previous_words: &[String]| -> Vec<String> {
-        if previous_words.is_empty() { return starters.clone(); }
+        self.pruned = self.chains.keys().copied().collect();
+    }
+
+    /// Remove the successors for which the closure returns true, given the context and the position in its list.
+    /// Contexts without any remaining successors are removed as well.
+    fn remove_where(&mut self, remove: impl Fn(Context, usize, &Successor) -> bool) -> Removed {
+        let mut removed = Removed::default();
+
+        self.chains.retain(|&key, successors| {
+            let count = successors.words.len();
+            let mut position = 0;
+
+            successors.words.retain(|successor| {
+                position += 1;
+                !remove(key, position - 1, successor)
+            });
+
+            if successors.words.len() != count {
+                removed.ngrams += count - successors.words.len();
+                successors.update_totals();
+            }
+
+            if successors.words.is_empty() { removed.contexts += 1; }
+            !successors.words.is_empty()
+        });
+
+        removed
+    }
+
+    /// For each n-gram of a context of at least one word, the probability of the context,
+    /// multiplied by the probability of the word after the context,
+    /// multiplied by the logarithmic ratio of that probability and the probability after the shorter context.
+    /// The n-grams whose shorter contexts have been removed are not considered.
+    fn entropy_costs(&self) -> Vec<(Context, StringId, f64)> {
+        let frequencies: HashMap<Context, HashMap<StringId, usize>> = self.chains.iter()
+            .filter(|(key, _)| key.len() < self.max_chain_len)
+            .map(|(&key, successors)| (key, successors.words.iter().map(|successor| (successor.word, successor.count)).collect()))
+            .collect();
+
+        let word_count = self.chains.get(&Context::new(&[])).map(|all| all.total).unwrap_or(0).max(1) as f64;
 
-        (1 ..= MAX_CHAIN_LEN.min(previous_words.len())).rev().flat_map(|chain_len| {
-            let sub_key_words = &previous_words[previous_words.len() - chain_len .. ];
-            println!("sub key: {:?}", sub_key_words);
+        self.chains.iter().filter(|(key, _)| !key.is_empty()).flat_map(|(&key, successors)| {
+            let shorter = self.chains.get(&key.shorter()).map(|shorter| shorter.total);
+            let frequencies = frequencies.get(&key.shorter());
+            let context_probability = successors.total as f64 / word_count;
 
-            let key_words: Vec<StringId> = sub_key_words.iter()
-                .flat_map(|string| strings.get(string))
+            successors.words.iter().filter_map(move |successor| {
+                let backoff = frequencies?.get(&successor.word)?;
+                let backoff = *backoff as f64 / shorter? as f64;
+                let probability = successor.count as f64 / successors.total as f64;
+
+                Some((key, successor.word, context_probability * probability * (probability / backoff).ln()))
+            })
+        })
+        .collect()
+    }
+
+    /// The number of bytes that `payload` will produce.
+    fn payload_size(&self) -> usize {
+        let strings = self.strings.len();
+        let string_bytes: usize = self.strings.iter().map(|(_, string)| string.len()).sum();
+        let successors = self.starters.words.len() + self.chains.values().map(|successors| successors.words.len()).sum::<usize>();
+
+        4 + 8 * section_count(self.max_chain_len)
+            + 4 * (strings + 1) + string_bytes + 4 * strings
+            + 4 * self.top_words.len() + 8 * 3 * (self.max_chain_len + 1)
+            + SUCCESSOR_SIZE * successors + 8 * LIST_FIELDS
+            + self.chains.keys().map(|&key| context_size(key)).sum::<usize>()
+    }
+
+    /// The Kneser-Ney discounts for counts of one, two, and three or more, for each context length.
+    fn discounts(&self) -> Vec<[f64; 3]> {
+        if !self.discounts.is_empty() { return self.discounts.clone(); }
+
+        let mut count_of_counts = vec![[0_usize; 4]; self.max_chain_len + 1];
+
+        for (key, successors) in &self.chains {
+            let highest = key.len() == self.max_chain_len;
+
+            for successor in &successors.words {
+                let count = if highest { successor.count } else { successor.continuation };
+                if (1 ..= 4).contains(&count) { count_of_counts[key.len()][count - 1] += 1; }
+            }
+        }
+
+        count_of_counts.into_iter().map(modified_discounts).collect()
+    }
+
+    /// Lay out the model as the sections of a cache file, which can be queried in place.
+    fn payload(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        let resolve = |id: StringId| self.strings.resolve(id).unwrap();
+
+        writer.strings((0 .. self.strings.len()).map(resolve));
+
+        let mut sorted_strings: Vec<StringId> = (0 .. self.strings.len()).collect();
+        sorted_strings.sort_by_key(|&id| resolve(id));
+        writer.array(sorted_strings.into_iter().map(|id| id as u32));
+
+        writer.array(self.top_words.iter().map(|&id| id as u32));
+        writer.array(self.discounts().into_iter().flat_map(|discounts| discounts.to_vec()));
+
+        // the contexts of each length, sorted by their words
+        let mut levels: Vec<Vec<(Vec<StringId>, &Successors)>> = (0 ..= self.max_chain_len).map(|_| Vec::new()).collect();
+
+        for (key, successors) in &self.chains {
+            levels[key.len()].push((key.words(), successors));
+        }
+
+        for level in &mut levels {
+            level.sort_by(|(key, _), (other, _)| key.cmp(other));
+        }
+
+        let lists: Vec<&Successors> = std::iter::once(&self.starters)
+            .chain(levels.iter().flat_map(|level| level.iter().map(|&(_, successors)| successors)))
+            .collect();
+
+        let successors = || lists.iter().flat_map(|successors| successors.words.iter());
+        writer.array(successors().map(|successor| successor.word as u32));
+        writer.array(successors().map(|successor| successor.count as u64));
+        writer.array(successors().map(|successor| successor.continuation as u64));
+
+        writer.array(lists.iter().flat_map(|successors| {
+            let mut positions: Vec<u32> = (0 .. successors.words.len() as u32).collect();
+            positions.sort_by_key(|&position| successors.words[position as usize].word);
+            positions
+        }));
+
+        let mut start = 0;
+        let mut fields = |successors: &Successors| {
+            let fields = [
+                start, successors.words.len(), successors.total, successors.continuation_total,
+                successors.classes[0], successors.classes[1], successors.classes[2],
+                successors.continuation_classes[0], successors.continuation_classes[1], successors.continuation_classes[2],
+            ];
+
+            start += successors.words.len();
+            IntoIterator::into_iter(fields).map(|field| field as u64)
+        };
+
+        writer.array(fields(&self.starters));
+
+        for level in &levels {
+            writer.array(level.iter().flat_map(|(key, _)| key.iter().map(|&id| id as u32)));
+            writer.array(level.iter().flat_map(|&(_, successors)| fields(successors)).collect::<Vec<u64>>());
+        }
+
+        writer.finish()
+    }
+}
+
+impl MappedModel {
+    fn strings(&self) -> Strings<'_> {
+        self.sections.strings(STRING_OFFSETS, STRING_BYTES)
+    }
+
+    fn resolve(&self, id: StringId) -> &str {
+        self.strings().get(id)
+    }
+
+    /// Find the id of the word, using the sorted string ids.
+    fn id(&self, word: &str) -> Option<StringId> {
+        let sorted = self.sections.array::<u32>(SORTED_STRINGS);
+        let index = sorted.partition_point(|id| self.resolve(id as StringId) < word);
+
+        Some(index).filter(|&index| index < sorted.len())
+            .map(|index| sorted.get(index) as StringId)
+            .filter(|&id| self.resolve(id) == word)
+    }
+
+    fn is_top_word(&self, id: StringId) -> bool {
+        self.sections.array::<u32>(TOP_WORDS).iter().any(|top_word| top_word as StringId == id)
+    }
+
+    fn discounts(&self, chain_len: usize) -> [f64; 3] {
+        let discounts = self.sections.array::<f64>(DISCOUNTS);
+        [ discounts.get(chain_len * 3), discounts.get(chain_len * 3 + 1), discounts.get(chain_len * 3 + 2) ]
+    }
+
+    /// The successors of the context, found by a binary search through the sorted contexts of the same length.
+    fn successors(&self, context: &[StringId]) -> Option<List<'_>> {
+        let chain_len = context.len();
+        let keys = self.sections.array::<u32>(CONTEXTS + 2 * chain_len);
+        let lists = self.sections.array::<u64>(CONTEXTS + 2 * chain_len + 1);
+
+        let key = |index: usize| keys.slice(index * chain_len .. (index + 1) * chain_len).iter();
+        let context = || context.iter().map(|&id| id as u32);
+
+        let count = lists.len() / LIST_FIELDS;
+        let index = crate::mapped::partition_point(count, |index| key(index).lt(context()));
+
+        Some(index).filter(|&index| index < count && key(index).eq(context()))
+            .map(|index| List::read(&self.sections, lists, index))
+    }
+
+    /// The ids of the last known words, up to the maximum chain length.
+    fn context(&self, previous_words: &[String]) -> Vec<StringId> {
+        let mut context: Vec<StringId> = previous_words.iter().rev().take(self.max_chain_len)
+            .map(|word| self.id(word))
+            .take_while(Option::is_some).map(Option::unwrap)
+            .collect();
+
+        context.reverse();
+        context
+    }
+
+    /// Predict the most likely next words, excluding the most common words.
+    /// Without previous words, the most common sentence starters are predicted.
+    pub fn predict(&self, previous_words: &[String], count: usize, smoothing: Smoothing) -> Vec<Prediction> {
+        let resolve = |id: StringId| self.resolve(id).to_owned();
+        let is_top_word = |successor: &Successor| self.is_top_word(successor.word);
+
+        if previous_words.is_empty() {
+            let starters = List::read(&self.sections, self.sections.array(STARTERS), 0);
+
+            return starters.iter().filter(|successor| !is_top_word(successor)).take(count)
+                .map(|successor| Prediction {
+                    word: resolve(successor.word), count: successor.count, order: 0,
+                    probability: successor.count as f64 / starters.total.max(1) as f64,
+                })
                 .collect();
+        }
+
+        let context = self.context(previous_words);
+        let chains = (1 ..= context.len()).rev().filter_map(|chain_len| {
+            let key = &context[context.len() - chain_len ..];
+            println!("sub key: {:?}", key.iter().map(|&id| self.resolve(id)).collect::<Vec<_>>());
+            self.successors(key).map(|successors| (chain_len, successors))
+        });
+
+        match smoothing {
+            Smoothing::Heuristic => chains
+                .flat_map(|(chain_len, successors)| {
+                    successors.iter().filter(|successor| !is_top_word(successor)).map(move |successor| Prediction {
+                        word: resolve(successor.word), count: successor.count, order: chain_len,
+                        probability: successor.count as f64 / successors.total.max(1) as f64,
+                    })
+                })
+                .take(count).collect(),
+
+            Smoothing::KneserNey => {
+                // score the most common successors of each context, remembering the longest context they followed
+                let mut candidates: HashMap<StringId, (usize, usize)> = HashMap::new();
+
+                for (chain_len, successors) in chains {
+                    let common = successors.iter().filter(|successor| !is_top_word(successor));
+
+                    for successor in common.take(count * CANDIDATES_PER_PREDICTION) {
+                        candidates.entry(successor.word).or_insert((successor.count, chain_len));
+                    }
+                }
+
+                let mut predictions: Vec<Prediction> = candidates.into_iter()
+                    .map(|(id, (count, order))| Prediction {
+                        word: resolve(id), count, order,
+                        probability: self.kneser_ney(Some(id), &context),
+                    })
+                    .collect();
+
+                predictions.sort_by(|a, b| b.probability.partial_cmp(&a.probability)
+                    .unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.word.cmp(&b.word)));
+
+                predictions.truncate(count);
+                predictions
+            },
+        }
+    }
+
+    /// Predict the most likely phrases of two to `max_len` words after the previous words, most likely first,
+    /// using a beam search that only extends the `beam_width` most likely phrases of each length.
+    /// Unlike single predictions, phrases may contain the most common words.
+    /// A phrase ends early if its last words have no known successors.
+    pub fn phrases(&self, previous_words: &[String], max_len: usize, beam_width: usize, smoothing: Smoothing) -> Vec<Phrase> {
+        let mut beam = vec![ Phrase { words: Vec::new(), probability: 1.0 } ];
+
+        for _ in 0 .. max_len {
+            let mut extended = false;
+            let mut phrases = Vec::new();
+
+            for phrase in beam {
+                let mut words = previous_words.to_vec();
+                words.extend(phrase.words.iter().cloned());
+
+                let successors = self.likely_successors(&words, beam_width, smoothing);
+                if successors.is_empty() { phrases.push(phrase); continue; }
+
+                extended = true;
+
+                for (word, probability) in successors {
+                    let mut words = phrase.words.clone();
+                    words.push(word);
+                    phrases.push(Phrase { words, probability: phrase.probability * probability });
+                }
+            }
+
+            phrases.sort_by(|a, b| b.probability.partial_cmp(&a.probability)
+                .unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.words.cmp(&b.words)));
+
+            phrases.truncate(beam_width);
+            beam = phrases;
+
+            if !extended { break; }
+        }
+
+        beam.retain(|phrase| phrase.words.len() > 1);
+        beam
+    }
+
+    /// The most common successors of the longest contexts of the previous words, along with their probabilities.
+    /// Without previous words, these are the most common sentence starters.
+    fn likely_successors(&self, previous_words: &[String], count: usize, smoothing: Smoothing) -> Vec<(String, f64)> {
+        let context = self.context(previous_words);
+
+        if context.is_empty() {
+            // the last word is unknown, so nothing is known about its successors
+            if !previous_words.is_empty() { return Vec::new(); }
+
+            let starters = List::read(&self.sections, self.sections.array(STARTERS), 0);
+            return starters.iter().take(count)
+                .map(|successor| (self.resolve(successor.word).to_owned(), successor.count as f64 / starters.total.max(1) as f64))
+                .collect();
+        }
+
+        let mut candidates: Vec<StringId> = Vec::new();
+
+        for chain_len in (1 ..= context.len()).rev() {
+            if candidates.len() >= count { break; }
+
+            if let Some(successors) = self.successors(&context[context.len() - chain_len ..]) {
+                for successor in successors.iter().take(count) {
+                    if !candidates.contains(&successor.word) { candidates.push(successor.word); }
+                }
+            }
+        }
+
+        candidates.into_iter()
+            .map(|id| (self.resolve(id).to_owned(), match smoothing {
+                Smoothing::Heuristic => self.heuristic(Some(id), &context),
+                Smoothing::KneserNey => self.kneser_ney(Some(id), &context),
+            }))
+            .collect()
+    }
+
+    /// The probability of the word after the previous words.
+    pub fn probability(&self, word: &str, previous_words: &[String], smoothing: Smoothing) -> f64 {
+        let word = self.id(word);
+        let context = self.context(previous_words);
+
+        match smoothing {
+            Smoothing::Heuristic => self.heuristic(word, &context),
+            Smoothing::KneserNey => self.kneser_ney(word, &context),
+        }
+    }
+
+    /// The probability of the first following words after the word and its previous words,
+    /// which is how well the word leads into the text after it. Reads the n-grams that end in the following words,
+    /// such that only following words within the maximum chain length of the word are taken into account.
+    ///
+    /// This uses the forward n-grams instead of a model of the reversed sentences: multiplied by the probability
+    /// of the word after the previous words, it is proportional to the probability of the word given the text on
+    /// both sides, while the reverse probability of the word before the following words would count how common
+    /// the word is a second time. No reverse model is therefore built.
+    pub fn right_context_probability(&self, previous_words: &[String], word: &str, next_words: &[String], smoothing: Smoothing) -> f64 {
+        let mut words = previous_words.to_vec();
+        words.push(word.to_string());
 
-            let options = chains.get(&key_words);
-            options.into_iter().flatten()
-                .filter(|&id| !top_words.contains(id))
-                .map(|&id| strings.resolve(id).unwrap().to_owned())
+        let mut probability = 1.0;
 
-        }).take(7).collect()
+        for next_word in next_words.iter().take(self.max_chain_len) {
+            probability *= self.probability(next_word, &words, smoothing);
+            words.push(next_word.clone());
+        }
+
+        probability
+    }
+
+    fn heuristic(&self, word: Option<StringId>, context: &[StringId]) -> f64 {
+        for chain_len in (1 ..= context.len()).rev() {
+            if let Some(successors) = self.successors(&context[context.len() - chain_len ..]) {
+                if let Some(successor) = word.and_then(|word| successors.find(word)) {
+                    return successor.count as f64 / successors.total as f64;
+                }
+            }
+        }
+
+        // add-one smoothing of the word frequency, such that unknown words are possible
+        let all = self.successors(&[]);
+        let count = all.and_then(|all| word.and_then(|word| all.find(word))).map(|successor| successor.count).unwrap_or(0);
+        let total = all.map(|all| all.total).unwrap_or(0);
+        (count + 1) as f64 / (total + self.strings().len() + 1) as f64
+    }
+
+    /// The sum of the heuristic probabilities of all known words and one unknown word after the context,
+    /// which differs from one, as the successors of each shorter context are only used for the words
+    /// that do not follow the longer contexts, and the word frequencies for the words that follow none of them.
+    fn heuristic_total(&self, context: &[StringId]) -> f64 {
+        let mut found: HashSet<StringId> = HashSet::new();
+        let mut total = 0.0;
+
+        for chain_len in (1 ..= context.len()).rev() {
+            if let Some(successors) = self.successors(&context[context.len() - chain_len ..]) {
+                for successor in successors.iter() {
+                    if found.insert(successor.word) { total += successor.count as f64 / successors.total as f64; }
+                }
+            }
+        }
+
+        // the smoothed word frequencies of all words add up to one, minus those of the words found above
+        let all = self.successors(&[]);
+        let smoothed_total = (all.map(|all| all.total).unwrap_or(0) + self.strings().len() + 1) as f64;
+
+        let found_total: usize = found.iter()
+            .map(|&word| all.and_then(|all| all.find(word)).map(|successor| successor.count).unwrap_or(0) + 1)
+            .sum();
+
+        total + (smoothed_total - found_total as f64) / smoothed_total
+    }
+
+    /// The interpolated modified Kneser-Ney probability of the word after the context.
+    /// All contexts shorter than the maximum chain length use continuation counts,
+    /// and the empty context is interpolated with the uniform distribution over all known words and one unknown word.
+    fn kneser_ney(&self, word: Option<StringId>, context: &[StringId]) -> f64 {
+        let lower = if context.is_empty() { 1.0 / (self.strings().len() + 1) as f64 }
+            else { self.kneser_ney(word, &context[1..]) };
+
+        let successors = match self.successors(context) {
+            Some(successors) => successors,
+            None => return lower,
+        };
+
+        let highest = context.len() == self.max_chain_len;
+
+        let (total, classes) = if highest { (successors.total, successors.classes) }
+            else { (successors.continuation_total, successors.continuation_classes) };
+
+        if total == 0 { return lower; }
+
+        let count = word.and_then(|word| successors.find(word))
+            .map(|successor| if highest { successor.count } else { successor.continuation })
+            .unwrap_or(0);
+
+        let discounts = self.discounts(context.len());
+        let discount = match count { 0 => 0.0, 1 => discounts[0], 2 => discounts[1], _ => discounts[2] };
+
+        let remaining = discounts.iter().zip(classes.iter())
+            .map(|(discount, &class)| discount * class as f64).sum::<f64>();
+
+        let total = total as f64;
+        (count as f64 - discount).max(0.0) / total + remaining / total * lower
+    }
+
+    /// Compute the perplexity of both smoothing methods on the held-out sentences.
+    /// The heuristic probabilities are divided by their sum over all words, such that they form a distribution.
+    pub fn evaluate(&self, held_out: &CorpusConfig, tokenizer: &dyn Tokenizer) -> Result<Evaluation> {
+        let mut evaluation = Evaluation { words: 0, unknown_words: 0, heuristic: 0.0, kneser_ney: 0.0 };
+
+        corpus::for_each_sentence(held_out, |_, sentence| {
+            let words = tokenizer.words(&sentence);
+
+            for index in 0 .. words.len() {
+                let (word, context) = (self.id(&words[index]), self.context(&words[.. index]));
+
+                evaluation.words += 1;
+                if word.is_none() { evaluation.unknown_words += 1; }
+
+                evaluation.heuristic -= (self.heuristic(word, &context) / self.heuristic_total(&context)).log2();
+                evaluation.kneser_ney -= self.kneser_ney(word, &context).log2();
+            }
+        })?;
+
+        let words = evaluation.words.max(1) as f64;
+        evaluation.heuristic = (evaluation.heuristic / words).exp2();
+        evaluation.kneser_ney = (evaluation.kneser_ney / words).exp2();
+        Ok(evaluation)
+    }
+}
+
+impl Successors {
+
+    /// Add the counts to the sorted list, and sort it again.
+    fn merge(&mut self, counts: Count<StringId>, continuations: Count<StringId>) {
+        let (mut counts, mut continuations) = (counts, continuations);
+
+        for successor in self.words.iter_mut() {
+            successor.count += counts.remove(&successor.word).unwrap_or(0);
+            successor.continuation += continuations.remove(&successor.word).unwrap_or(0);
+        }
+
+        self.words.extend(counts.into_iter().map(|(word, count)| Successor {
+            word, count, continuation: continuations.remove(&word).unwrap_or(0)
+        }));
+
+        // sort by number of occurrences, and by first occurrence in the corpus if equally common
+        self.words.sort_by(|a, b| b.count.cmp(&a.count).then(a.word.cmp(&b.word)));
+        self.update_totals();
+    }
+
+    /// Compute the totals and classes of the remaining successors.
+    fn update_totals(&mut self) {
+        self.total = self.words.iter().map(|successor| successor.count).sum();
+        self.continuation_total = self.words.iter().map(|successor| successor.continuation).sum();
+        self.classes = count_classes(self.words.iter().map(|successor| successor.count));
+        self.continuation_classes = count_classes(self.words.iter().map(|successor| successor.continuation));
     }
 }
 
+impl<'m> List<'m> {
+
+    /// Read the fields of the list with that index.
+    fn read(sections: &'m Sections, lists: Array<'m, u64>, index: usize) -> Self {
+        let field = |field: usize| lists.get(index * LIST_FIELDS + field) as usize;
+
+        List {
+            sections,
+            start: field(0), len: field(1),
+            total: field(2), continuation_total: field(3),
+            classes: [ field(4), field(5), field(6) ],
+            continuation_classes: [ field(7), field(8), field(9) ],
+        }
+    }
+
+    fn get(&self, position: usize) -> Successor {
+        let index = self.start + position;
+
+        Successor {
+            word: self.sections.array::<u32>(SUCCESSOR_WORDS).get(index) as StringId,
+            count: self.sections.array::<u64>(SUCCESSOR_COUNTS).get(index) as usize,
+            continuation: self.sections.array::<u64>(SUCCESSOR_CONTINUATIONS).get(index) as usize,
+        }
+    }
+
+    /// All successors, most common first.
+    fn iter(self) -> impl Iterator<Item = Successor> + 'm {
+        (0 .. self.len).map(move |position| self.get(position))
+    }
+
+    /// Find the word, using the positions sorted by word.
+    fn find(&self, word: StringId) -> Option<Successor> {
+        let positions = self.sections.array::<u32>(SUCCESSOR_INDEX).slice(self.start .. self.start + self.len);
+        let index = positions.partition_point(|position| self.get(position as usize).word < word);
+
+        Some(index).filter(|&index| index < self.len)
+            .map(|index| self.get(positions.get(index) as usize))
+            .filter(|successor| successor.word == word)
+    }
+}
+
+/// The bytes of the key and the list of a context in the `CONTEXTS` sections.
+fn context_size(key: Context) -> usize {
+    4 * key.len() + 8 * LIST_FIELDS
+}
+
+/// How many of the counts are one, two, and three or more.
+fn count_classes(counts: impl Iterator<Item = usize>) -> [usize; 3] {
+    let mut classes = [0; 3];
+
+    for count in counts {
+        if count > 0 { classes[count.min(3) - 1] += 1; }
+    }
+
+    classes
+}
+
+/// The discounts for counts of one, two, and three or more, as estimated by Chen and Goodman,
+/// based on the number of n-grams that occur exactly one to four times.
+fn modified_discounts(count_of_counts: [usize; 4]) -> [f64; 3] {
+    if count_of_counts.contains(&0) {
+        return [0.5, 1.0, 1.5]; // too little data for estimating the discounts
+    }
+
+    let [n1, n2, n3, n4] = [count_of_counts[0] as f64, count_of_counts[1] as f64, count_of_counts[2] as f64, count_of_counts[3] as f64];
+    let y = n1 / (n1 + 2.0 * n2);
+
+    [
+        (1.0 - 2.0 * y * n2 / n1).max(0.0),
+        (2.0 - 3.0 * y * n3 / n2).max(0.0),
+        (3.0 - 4.0 * y * n4 / n3).max(0.0),
+    ]
+}
+
+/// Map the word chains of the cache file, unless it is outdated.
+pub fn load(
+    corpus: &CorpusConfig, cache: &Path, max_chain_len: usize, pruning: &Pruning,
+    tokenizer: &dyn Tokenizer, rebuild: Rebuild
+) -> Result<(Option<MappedModel>, Manifest)> {
+    let contents = contents(max_chain_len, pruning, tokenizer);
+    let (sections, manifest) = cache::load(cache, &contents, corpus, rebuild)?;
+    Ok((sections.map(|sections| MappedModel { max_chain_len, sections }), manifest))
+}
+
+/// Write the word chains to the cache file, and return them in the same layout.
+/// The pruning rules are only recorded, they must have been applied to the model before.
+pub fn store(cache: &Path, manifest: Manifest, model: &Model, pruning: &Pruning, tokenizer: &dyn Tokenizer) -> Result<MappedModel> {
+    let max_chain_len = model.max_chain_len;
+    let payload = model.payload();
+
+    cache::store(cache, contents(max_chain_len, pruning, tokenizer), manifest, &payload)?;
+
+    let sections = Sections::read(Bytes::from(payload), section_count(max_chain_len)).expect("invalid prediction payload");
+    Ok(MappedModel { max_chain_len, sections })
+}
+
+/// Return a lambda that predicts the next words, based on up to `max_chain_len` previous words.
+pub fn ngram_predictor(model: Arc<MappedModel>, count: usize, smoothing: Smoothing) -> impl Fn(&[String]) -> Vec<Prediction> {
+    move |previous_words: &[String]| model.predict(previous_words, count, smoothing)
+}
+
+/// Return a lambda that predicts the most likely phrases of up to `max_len` words after the previous words.
+pub fn phrase_predictor(model: Arc<MappedModel>, max_len: usize, beam_width: usize, smoothing: Smoothing) -> impl Fn(&[String]) -> Vec<Phrase> {
+    move |previous_words: &[String]| model.phrases(previous_words, max_len, beam_width, smoothing)
+}
+
+/// Return a lambda that computes how likely the words after the cursor follow a word and the words before it.
+pub fn right_context_scorer(model: Arc<MappedModel>, smoothing: Smoothing) -> impl Fn(&[String], &str, &[String]) -> f64 {
+    move |previous_words: &[String], word: &str, next_words: &[String]|
+        model.right_context_probability(previous_words, word, next_words, smoothing)
+}
+
+/// Merge the new files of the directory into the existing prediction cache, and prune the changed contexts.
+/// Without pruning, the merged cache predicts the same as building it from scratch. Otherwise the counts of the
+/// n-grams that were pruned before are lost, and the discounts are kept, so the merged cache differs.
+pub fn append(
+    corpus: &CorpusConfig, cache: &Path, max_chain_len: usize, pruning: &Pruning,
+    tokenizer: &dyn Tokenizer, directory: &Path
+) -> Result<()> {
+    let contents = contents(max_chain_len, pruning, tokenizer);
+
+    cache::append(cache, &contents, corpus, directory, |sections: &Sections, files: &[ManifestEntry]| {
+        let mut model = Model::read(sections, max_chain_len);
+        model.add_files(files, corpus.on_error, tokenizer)?;
+        model.prune(pruning);
+        Ok(model.payload())
+    })
+}
+
+fn contents(max_chain_len: usize, pruning: &Pruning, tokenizer: &dyn Tokenizer) -> Contents {
+    Contents::Prediction { max_chain_len, pruning: pruning.clone(), tokenizer: tokenizer.signature() }
+}
+
 pub fn _gpt2_predictor() -> impl (Fn(&str) -> Vec<(Option<String>, Vec<String>)>) {
     use rust_bert::pipelines::generation::{GPT2Generator, LanguageGenerator, GenerateConfig};
     use rust_bert::gpt2::*;
@@ -172,11 +1256,12 @@ pub fn _gpt2_predictor() -> impl (Fn(&str) -> Vec<(Option<String>, Vec<String>)>
     }).unwrap();
 
     let model = std::sync::Arc::new(std::sync::Mutex::new(model));
+    let tokenizer = UnicodeTokenizer::default();
 
     move |base| {
         // generate a few predictions at once, using the GTP-2 generator
         println!("generating gpt-2 variations for \"{}\"", base);
-        debug_assert!(split_to_words(base).len() <= max_base_word_count);
+        debug_assert!(tokenizer.words(base).len() <= max_base_word_count);
 
         model.lock().unwrap()
             .generate(if !base.trim().is_empty() { Some(vec![base]) } else { None }, None).into_iter()
@@ -185,7 +1270,7 @@ pub fn _gpt2_predictor() -> impl (Fn(&str) -> Vec<(Option<String>, Vec<String>)>
 
                 // remove the first few words which we gave the predictor
                 let predictions = &prediction[base.len() ..];
-                let mut words = split_to_words(predictions);
+                let mut words = tokenizer.words(predictions);
 
                 if !predictions.starts_with(char::is_whitespace) {
                     let completion = words.remove(0);
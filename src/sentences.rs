@@ -0,0 +1,254 @@
+//! Splitting the text of a corpus file into sentences.
+//!
+//! Lines are joined into paragraphs, merging words that have been hyphenated at the end of a line,
+//! and an empty line always ends a sentence. Within a paragraph, a sentence ends with `.`, `!`, `?` or an ellipsis,
+//! followed by whitespace and a word that does not start with a lowercase letter.
+//! Periods within numbers and URLs are never followed by whitespace, and periods after
+//! abbreviations and initials are recognized, such that they do not produce bogus sentence starters.
+
+/// Abbreviations that are followed by another word of the same sentence, such as titles.
+/// They never end a sentence. Compared in lowercase, without the final period.
+const TITLES: &[&str] = &[
+    "mr", "mrs", "ms", "dr", "prof", "st", "mt", "gen", "lt", "col", "capt", "sgt", "rev", "hon", "pres", "gov", "sen", "rep",
+    "no", "nos", "vol", "vols", "fig", "figs", "p", "pp", "ch", "sec", "art", "vs", "cf", "e.g", "i.e", "viz", "approx", "ca",
+];
+
+/// Abbreviations that may also end a sentence, which they do if the next word is capitalized.
+const ABBREVIATIONS: &[&str] = &[
+    "etc", "al", "inc", "ltd", "co", "corp", "jr", "sr", "a.m", "p.m", "u.s", "u.k", "ph.d", "b.a", "m.a", "esp", "dept", "univ",
+    "jan", "feb", "mar", "apr", "jun", "jul", "aug", "sep", "sept", "oct", "nov", "dec",
+];
+
+const TERMINATORS: &[char] = &['.', '!', '?', '…', '。', '！', '？'];
+const OPENING: &[char] = &['"', '\'', '“', '‘', '«', '„', '(', '['];
+const CLOSING: &[char] = &['"', '\'', '”', '’', '»', ')', ']'];
+
+/// The sentences of the text, without their final punctuation.
+/// The text after the last sentence of the file is not returned, as it might be incomplete.
+pub fn split_to_sentences(text: &str) -> Vec<String> {
+    let paragraphs = paragraphs(text);
+    let last = paragraphs.len().saturating_sub(1);
+
+    paragraphs.iter().enumerate()
+        .flat_map(|(index, paragraph)| split_paragraph(paragraph, index == last))
+        .collect()
+}
+
+/// Join the lines of each paragraph with spaces, and remove the hyphens of words that continue on the next line.
+/// Hyphens before an uppercase continuation are part of a compound like Anglo-Saxons, and are kept without a space.
+fn paragraphs(text: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut paragraph = String::new();
+
+    for line in text.lines() { // also removes carriage returns
+        let line = line.trim();
+
+        if line.is_empty() {
+            if !paragraph.is_empty() { paragraphs.push(std::mem::take(&mut paragraph)); }
+            continue;
+        }
+
+        let hyphenated = paragraph.ends_with('-') && paragraph[.. paragraph.len() - 1].ends_with(char::is_alphabetic);
+
+        let compound = hyphenated && line.starts_with(char::is_uppercase);
+
+        if hyphenated && line.starts_with(char::is_lowercase) { paragraph.pop(); }
+        else if !paragraph.is_empty() && !compound { paragraph.push(' '); }
+
+        paragraph.push_str(line);
+    }
+
+    if !paragraph.is_empty() { paragraphs.push(paragraph); }
+    paragraphs
+}
+
+/// Split the paragraph at the sentence ends, and remove URLs and email addresses.
+/// The last sentence of a paragraph does not need final punctuation, unless the paragraph is incomplete.
+fn split_paragraph(paragraph: &str, incomplete: bool) -> Vec<String> {
+    // scripts without spaces do not separate sentences with whitespace either
+    let paragraph = paragraph.replace('。', "。 ").replace('！', "！ ").replace('？', "？ ");
+    let tokens: Vec<&str> = paragraph.split_whitespace().collect();
+
+    let mut sentences = Vec::new();
+    let mut sentence: Vec<&str> = Vec::new();
+
+    for (index, &token) in tokens.iter().enumerate() {
+        let core = token.trim_end_matches(CLOSING);
+        let word = core.trim_end_matches(TERMINATORS);
+        let terminators = &core[word.len() ..];
+
+        let is_end = !terminators.is_empty() && match tokens.get(index + 1) {
+            None => true,
+            Some(next) => ends_sentence(word.trim_start_matches(OPENING), terminators, next),
+        };
+
+        let word = if is_end { word } else { token };
+        if !is_url(word.trim_matches(OPENING).trim_end_matches(CLOSING)) { sentence.push(word); }
+
+        if is_end {
+            push_sentence(&mut sentences, &sentence);
+            sentence.clear();
+        }
+    }
+
+    if !incomplete { push_sentence(&mut sentences, &sentence); }
+    sentences
+}
+
+fn push_sentence(sentences: &mut Vec<String>, words: &[&str]) {
+    let sentence = words.join(" ");
+    let sentence = sentence.trim_end_matches(CLOSING).trim_end_matches(TERMINATORS);
+
+    if sentence.chars().any(char::is_alphanumeric) {
+        sentences.push(sentence.to_string());
+    }
+}
+
+/// The beginning of the text, up to the end of its first sentence, without the final punctuation.
+/// Empty if the text starts with final punctuation or an empty line, such that it does not continue the sentence before it.
+pub fn first_sentence(text: &str) -> String {
+    // the rest of the line after the cursor and the next line are empty
+    let starts_paragraph = text.lines().take(2).filter(|line| line.trim().is_empty()).count() == 2;
+    if starts_paragraph || text.trim_start().starts_with(TERMINATORS) { return String::new(); }
+
+    paragraphs(text).first()
+        .and_then(|paragraph| split_paragraph(paragraph, false).into_iter().next())
+        .unwrap_or_default()
+}
+
+/// Whether the next word starts a new sentence, because the text is empty or ends with final punctuation,
+/// which does not belong to a title or an initial.
+pub fn is_sentence_start(text: &str) -> bool {
+    let text = text.trim_end().trim_end_matches(CLOSING);
+    if text.is_empty() { return true; }
+
+    let word = text.rsplit(char::is_whitespace).next().unwrap_or("").trim_start_matches(OPENING);
+    let stripped = word.trim_end_matches(TERMINATORS);
+
+    stripped.len() != word.len() && ends_sentence(stripped, &word[stripped.len() ..], "")
+}
+
+/// Whether the word, followed by the terminators, ends the sentence, given the next word.
+fn ends_sentence(word: &str, terminators: &str, next: &str) -> bool {
+    let next_is_lowercase = next.trim_start_matches(OPENING).starts_with(char::is_lowercase);
+    if next_is_lowercase { return false; }
+
+    if terminators == "." {
+        let lowercase = word.to_lowercase();
+
+        let is_initial = word.chars().count() == 1 && word.starts_with(char::is_uppercase);
+        if is_initial || TITLES.contains(&lowercase.as_str()) { return false; }
+
+        // abbreviations end the sentence if the next word is capitalized
+        if ABBREVIATIONS.contains(&lowercase.as_str()) {
+            return next.trim_start_matches(OPENING).starts_with(char::is_uppercase);
+        }
+    }
+
+    true
+}
+
+/// Whether the word is a web address or an email address, which are not part of the language.
+fn is_url(word: &str) -> bool {
+    let lowercase = word.to_lowercase();
+    let is_email = word.find('@').map(|at| at > 0 && word[at ..].contains('.')).unwrap_or(false);
+
+    is_email || lowercase.starts_with("http://") || lowercase.starts_with("https://") || lowercase.starts_with("www.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_to_sentences;
+
+    fn sentences(text: &str) -> Vec<String> {
+        split_to_sentences(text)
+    }
+
+    #[test]
+    fn splits_at_punctuation() {
+        assert_eq!(sentences("This is one. Is this two? Yes! "), vec![ "This is one", "Is this two", "Yes" ]);
+        assert_eq!(sentences("What?! Really..."), vec![ "What", "Really" ]);
+    }
+
+    #[test]
+    fn drops_incomplete_sentence_at_end() {
+        assert_eq!(sentences("A complete sentence. An incomplete"), vec![ "A complete sentence" ]);
+        assert!(sentences("").is_empty());
+        assert!(sentences("...").is_empty());
+    }
+
+    #[test]
+    fn keeps_abbreviations_and_initials() {
+        assert_eq!(sentences("Dr. Smith met Mr. J. R. Jones. They talked."), vec![ "Dr. Smith met Mr. J. R. Jones", "They talked" ]);
+        assert_eq!(sentences("Bring fruit, e.g. Apples or pears."), vec![ "Bring fruit, e.g. Apples or pears" ]);
+        assert_eq!(sentences("See fig. 3 for details."), vec![ "See fig. 3 for details" ]);
+    }
+
+    #[test]
+    fn abbreviations_end_sentences_before_capitalized_words() {
+        assert_eq!(sentences("He bought apples, pears etc. Then he left."), vec![ "He bought apples, pears etc", "Then he left" ]);
+        assert_eq!(sentences("Apples, pears etc. are fruit."), vec![ "Apples, pears etc. are fruit" ]);
+        assert_eq!(sentences("She moved to the U.S. Her sister did not."), vec![ "She moved to the U.S", "Her sister did not" ]);
+    }
+
+    #[test]
+    fn keeps_numbers() {
+        assert_eq!(sentences("Pi is about 3.14 and not 3. It has 1,000.5 digits."), vec![ "Pi is about 3.14 and not 3", "It has 1,000.5 digits" ]);
+    }
+
+    #[test]
+    fn removes_urls_and_email_addresses() {
+        assert_eq!(
+            sentences("Visit https://example.com/a.b?c=d! Or www.example.org. Write to me@example.com today."),
+            vec![ "Visit", "Or", "Write to today" ]
+        );
+    }
+
+    #[test]
+    fn handles_quotes() {
+        assert_eq!(sentences("He said \"Stop.\" Then he left."), vec![ "He said \"Stop", "Then he left" ]);
+        assert_eq!(sentences("\"Help!\" he cried. \"Why?\" She asked."), vec![ "\"Help!\" he cried", "\"Why", "She asked" ]);
+        assert_eq!(sentences("“Fine.” ‘Good.’"), vec![ "“Fine", "‘Good" ]);
+    }
+
+    #[test]
+    fn handles_ellipses() {
+        assert_eq!(sentences("Wait... what happened? Nothing… Really."), vec![ "Wait... what happened", "Nothing", "Really" ]);
+        assert_eq!(sentences("And then… nothing."), vec![ "And then… nothing" ]);
+    }
+
+    #[test]
+    fn merges_hyphenated_words_across_lines() {
+        assert_eq!(sentences("This is a hyphen-\nated word.\r\nA well-\r\nknown fact."), vec![ "This is a hyphenated word", "A wellknown fact" ]);
+        assert_eq!(sentences("Keep the dash -\nhere. And the Anglo-\nSaxons."), vec![ "Keep the dash - here", "And the Anglo-Saxons" ]);
+    }
+
+    #[test]
+    fn joins_lines_and_splits_paragraphs() {
+        assert_eq!(sentences("A sentence\nacross lines.\n\nA heading\n\nThe text."), vec![ "A sentence across lines", "A heading", "The text" ]);
+    }
+
+    #[test]
+    fn detects_sentence_starts() {
+        assert!(super::is_sentence_start(""));
+        assert!(super::is_sentence_start("It rained. "));
+        assert!(super::is_sentence_start("\"Why?\""));
+        assert!(!super::is_sentence_start("It rained"));
+        assert!(!super::is_sentence_start("I met Dr. "));
+    }
+
+    #[test]
+    fn finds_first_sentence() {
+        assert_eq!(super::first_sentence(" is on the table. It is red"), "is on the table");
+        assert_eq!(super::first_sentence("ing at Dr. Smith"), "ing at Dr. Smith");
+        assert_eq!(super::first_sentence("\nthe table. It"), "the table");
+        assert_eq!(super::first_sentence(". The end"), "");
+        assert_eq!(super::first_sentence("\n\nA new paragraph."), "");
+        assert_eq!(super::first_sentence(""), "");
+    }
+
+    #[test]
+    fn splits_scripts_without_spaces() {
+        assert_eq!(sentences("今日は晴れです。明日は雨です。"), vec![ "今日は晴れです", "明日は雨です" ]);
+    }
+}
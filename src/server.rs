@@ -8,22 +8,82 @@ use serde::{Deserialize, Serialize};
 use std::time::Instant as time;
 use std::io::{Read};
 use tiny_http::{StatusCode};
+use crate::error::{Error, Result};
 
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
     pub previous: String,
-    pub next: String
+    pub next: String,
+
+    /// The language of the text. It is identified from the previous text if it is not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
-    pub completions: Vec<String>,
-    pub predictions: Vec<String>,
+    pub completions: Vec<Suggestion>,
+    pub predictions: Vec<Suggestion>,
+
+    /// Sequences of words that might follow the completed word.
+    #[serde(default)]
+    pub phrases: Vec<PhraseSuggestion>,
+
+    /// The language of the models that computed the suggestions, empty for the unnamed language.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub language: String,
+}
+
+/// A suggested word, along with how likely it is and how it was found.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub word: String,
+
+    /// The estimated probability of the word, between zero and one.
+    pub score: f64,
+
+    pub source: SuggestionSource,
+
+    /// The number of previous words that the n-gram prediction is based on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<usize>,
+
+    /// The number of edits between the typed word and the corrected prefix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edit_distance: Option<usize>,
 }
 
-/// Start a server that answers JSON suggestion requests with JSON suggestions.
-pub fn run(compute_suggestions: impl Fn(Request) -> Response) {
+/// A suggested sequence of words, along with how likely it is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PhraseSuggestion {
+    pub words: Vec<String>,
+
+    /// The estimated probability of the whole phrase, between zero and one.
+    pub score: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SuggestionSource {
+
+    /// A word that starts with the typed prefix.
+    PrefixCompletion,
+
+    /// A word that followed the previous words in the corpus.
+    Ngram,
+
+    /// A word that starts with a slightly different prefix than the typed one.
+    Correction,
+
+    /// A word that often starts a sentence.
+    SentenceStarter,
+}
+
+/// Start a server on the address (for example `localhost:3000`)
+/// that answers JSON suggestion requests with JSON suggestions.
+/// Only returns if the server cannot listen on the address.
+pub fn run(address: &str, compute_suggestions: impl Fn(Request) -> Response) -> Result<()> {
     let compute_answer = move |request: &mut dyn Read| -> std::io::Result<String> {
         let request: Request = serde_json::from_reader(request)?;
 
@@ -38,8 +98,10 @@ pub fn run(compute_suggestions: impl Fn(Request) -> Response) {
         Ok(serde_json::to_string(&answer)?)
     };
 
-    let server = tiny_http::Server::http("localhost:3000").unwrap();
-    println!("starting server.");
+    let server = tiny_http::Server::http(address)
+        .map_err(|error| Error::Address(address.to_string(), error.to_string()))?;
+
+    println!("starting server on {}.", address);
 
     loop {
         let result = server.recv().map(|mut request| {
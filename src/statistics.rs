@@ -0,0 +1,118 @@
+use crate::corpus::{self, CorpusConfig};
+use crate::tokenizer::Tokenizer;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::fs;
+
+type Count<T> = HashMap<T, usize>;
+
+/// The word and character counts of the corpus. The weights of the sources are ignored.
+pub struct Statistics {
+    words: Count<String>,
+    chars: Count<char>,
+    word_starter_chars: Count<char>,
+    sentence_starter_chars: Count<char>,
+    word_count: u128,
+    char_count: u128,
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Statistics {
+            words: HashMap::new(),
+            chars: HashMap::new(),
+            word_starter_chars: HashMap::new(),
+            sentence_starter_chars: HashMap::new(),
+            word_count: 0,
+            char_count: 0,
+        }
+    }
+
+    /// Count the characters of the sentence, and its words, which have been split before.
+    pub fn add(&mut self, string: &str, sentence: &[String]) {
+        if sentence.is_empty() { return; }
+
+        self.word_count += sentence.len() as u128;
+
+        for char in string.chars() {
+            *self.chars.entry(char).or_insert(0) += 1;
+            self.char_count += 1;
+        }
+
+        *self.sentence_starter_chars.entry(sentence[0].chars().next().unwrap()).or_insert(0) += 1;
+
+        for word in sentence {
+            *self.word_starter_chars.entry(word.chars().next().unwrap()).or_insert(0) += 1;
+            *self.words.entry(word.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Add the counts of another part of the corpus.
+    pub fn merge(mut self, other: Statistics) -> Statistics {
+        fn add<T: std::hash::Hash + Eq>(counts: &mut Count<T>, more: Count<T>) {
+            for (value, count) in more { *counts.entry(value).or_insert(0) += count; }
+        }
+
+        add(&mut self.words, other.words);
+        add(&mut self.chars, other.chars);
+        add(&mut self.word_starter_chars, other.word_starter_chars);
+        add(&mut self.sentence_starter_chars, other.sentence_starter_chars);
+        self.word_count += other.word_count;
+        self.char_count += other.char_count;
+        self
+    }
+
+    /// Write `words.txt`, `chars.txt`, `corpus.txt`, `word-starter-chars.txt`
+    /// and `sentence-starter-chars.txt` into the output directory.
+    pub fn write(self, output: &Path) -> Result<()> {
+        fn map_to_sorted_count_vec<T: Ord>(map: impl Iterator<Item = (T, usize)>) -> Vec<(usize, T)> {
+            let mut counts: Vec<(usize, T)> = map.map(|(value, count)| (count, value)).collect();
+            counts.sort_by(|(count, value), (other_count, other)| other_count.cmp(count).then(value.cmp(other)));
+            counts
+        }
+
+        let Statistics { words, chars, word_starter_chars, sentence_starter_chars, word_count, char_count } = self;
+
+        let distinct_words = words.len();
+        let header = format!("// processed {} words\n// collected {} distinct words\n\n", word_count, distinct_words);
+
+        let words = map_to_sorted_count_vec(words.into_iter());
+        let chars = map_to_sorted_count_vec(chars.into_iter().map(|(char, count)| (char.to_string(), count)));
+        let word_starter_chars = map_to_sorted_count_vec(word_starter_chars.into_iter());
+        let sentence_starter_chars = map_to_sorted_count_vec(sentence_starter_chars.into_iter());
+
+        let write = |name: &str, contents: String| {
+            let path = output.join(name);
+            fs::write(&path, contents).map_err(|error| Error::Io(path, error))
+        };
+
+        fs::create_dir_all(output).map_err(|error| Error::Io(output.to_path_buf(), error))?;
+        write("words.txt", format!("{:#?}", words))?;
+        write("chars.txt", format!("{:#?}", chars))?;
+        write("word-starter-chars.txt", format!("{}{:#?}", header, word_starter_chars))?;
+        write("sentence-starter-chars.txt", format!("{}{:#?}", header, sentence_starter_chars))?;
+
+        write("corpus.txt", format!(
+            "words: {}, chars:{}, distinct words: {}",
+            word_count, char_count, distinct_words,
+        ))?;
+
+        println!("wrote statistics to {}", output.display());
+        Ok(())
+    }
+}
+
+/// Analyze the corpus and write the word and character statistics into the output directory.
+pub fn write_reports(corpus: &CorpusConfig, tokenizer: &dyn Tokenizer, output: &Path) -> Result<()> {
+    println!("analyzing corpus statistics...");
+
+    let statistics = corpus::fold_sentences(
+        corpus, Statistics::new,
+        |statistics, _, sentence| statistics.add(&sentence, &tokenizer.words(&sentence)),
+        Statistics::merge
+    )?;
+
+    println!("... analyzed all files");
+    statistics.write(output)
+}
@@ -0,0 +1,295 @@
+//! Splitting sentences into words.
+//!
+//! The caches record the signature of the tokenizer they were built with,
+//! such that the words of a query are always split the same way as the words of the corpus.
+
+use serde::{Deserialize, Serialize};
+
+/// Splits a sentence into the words that are counted and suggested.
+pub trait Tokenizer: Send + Sync {
+
+    /// The words of the sentence, in order, as they are written. May return an empty list.
+    fn surface_words(&self, sentence: &str) -> Vec<String>;
+
+    /// The word that is counted for a written word, for example in lowercase.
+    fn fold_case(&self, surface_word: &str) -> String;
+
+    /// The words of the sentence, in order, as they are counted.
+    fn words(&self, sentence: &str) -> Vec<String> {
+        self.surface_words(sentence).iter().map(|word| self.fold_case(word)).collect()
+    }
+
+    /// Describes the tokenizer and its options. Caches built with a different signature are not used.
+    fn signature(&self) -> String;
+}
+
+/// Finds words at the word boundaries of letters, digits and the punctuation within words,
+/// following the most important rules of Unicode Standard Annex #29, and only keeps the segments that contain letters or digits.
+/// Every ideograph and every hiragana character is a separate word, as there are no spaces in these scripts.
+/// Other scripts without spaces, like Thai, Lao, Khmer and Myanmar, would need a dictionary to find the words,
+/// so each of their runs between spaces or punctuation is a single word.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UnicodeTokenizer {
+
+    /// Keep words that contain digits, like "3D" or "1,000", instead of removing them.
+    pub digits: bool,
+
+    /// Keep compounds like "e-mail" as a single word, instead of splitting them at the hyphens.
+    pub hyphens: bool,
+
+    /// Split contractions like "don't" into "don" and "'t", instead of keeping them as a single word.
+    pub split_contractions: bool,
+
+    /// Keep the case of the words, instead of converting them to lowercase.
+    pub case: bool,
+}
+
+/// The word break property of a character, as far as it matters for finding words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Letter,
+    Katakana,
+    Ideograph,
+    Numeric,
+    MidLetter,
+    MidNum,
+    MidNumLet,
+    ExtendNumLet,
+    Extend,
+    Hyphen,
+    Other,
+}
+
+impl Tokenizer for UnicodeTokenizer {
+    fn surface_words(&self, sentence: &str) -> Vec<String> {
+        segments(sentence, self.hyphens).into_iter()
+            .flat_map(|segment| self.split_contractions(segment))
+            .filter(|word| self.digits || !word.chars().any(char::is_numeric))
+            .map(|word| self.normalize(word))
+            .filter(|word| !word.is_empty())
+            .collect()
+    }
+
+    fn fold_case(&self, surface_word: &str) -> String {
+        if self.case { surface_word.to_string() }
+        else { surface_word.to_lowercase() }
+    }
+
+    fn signature(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl UnicodeTokenizer {
+
+    /// Split the word before each apostrophe, if enabled.
+    fn split_contractions<'s>(&self, word: &'s str) -> Vec<&'s str> {
+        if !self.split_contractions { return vec![ word ]; }
+
+        let mut parts = Vec::new();
+        let mut start = 0;
+
+        for (index, char) in word.char_indices().skip(1) {
+            if is_apostrophe(char) {
+                parts.push(&word[start .. index]);
+                start = index;
+            }
+        }
+
+        parts.push(&word[start ..]);
+        parts
+    }
+
+    /// Unify the apostrophes and remove invisible characters.
+    fn normalize(&self, word: &str) -> String {
+        word.chars()
+            .filter(|&char| !matches!(char, '\u{AD}' | '\u{200B}' | '\u{2060}' | '\u{FEFF}'))
+            .map(|char| if is_apostrophe(char) { '\'' } else { char })
+            .collect()
+    }
+}
+
+/// The segments of the text that contain letters or digits.
+/// Optionally joins words that are only separated by a hyphen.
+fn segments(text: &str, hyphens: bool) -> Vec<&str> {
+    let chars: Vec<(usize, char, Class)> = text.char_indices().map(|(index, char)| (index, char, class(char))).collect();
+    let end_of = |position: usize| chars.get(position).map(|&(index, _, _)| index).unwrap_or(text.len());
+
+    // the position after any extending characters, which belong to the preceding character
+    let skip_extend = |mut position: usize| {
+        while chars.get(position).map(|&(_, _, class)| class) == Some(Class::Extend) { position += 1; }
+        position
+    };
+
+    let class_at = |position: usize| chars.get(position).map(|&(_, _, class)| class).unwrap_or(Class::Other);
+
+    let mut segments = Vec::new();
+    let mut position = 0;
+
+    while position < chars.len() {
+        let start = position;
+        let mut previous = class_at(position);
+
+        match previous {
+            Class::Ideograph => {
+                position = skip_extend(position + 1);
+                segments.push(&text[end_of(start) .. end_of(position)]);
+                continue;
+            },
+
+            Class::Letter | Class::Katakana | Class::Numeric | Class::ExtendNumLet => {},
+
+            _ => { position += 1; continue; },
+        }
+
+        position = skip_extend(position + 1);
+
+        loop {
+            let next = class_at(position);
+
+            if joins(previous, next) {
+                previous = next;
+                position = skip_extend(position + 1);
+                continue;
+            }
+
+            // a single separator between two letters or two digits, like in "can't", "U.S" or "3.14"
+            let after_separator = skip_extend(position + 1);
+            let following = class_at(after_separator);
+
+            let separated = match (previous, next, following) {
+                (Class::Letter, Class::MidLetter, Class::Letter) => true,
+                (Class::Letter, Class::MidNumLet, Class::Letter) => true,
+                (Class::Numeric, Class::MidNum, Class::Numeric) => true,
+                (Class::Numeric, Class::MidNumLet, Class::Numeric) => true,
+                (Class::Letter, Class::Hyphen, Class::Letter) | (Class::Letter, Class::Hyphen, Class::Numeric)
+                | (Class::Numeric, Class::Hyphen, Class::Letter) | (Class::Numeric, Class::Hyphen, Class::Numeric) => hyphens,
+                _ => false,
+            };
+
+            if !separated { break; }
+
+            previous = following;
+            position = skip_extend(after_separator + 1);
+        }
+
+        let segment = &text[end_of(start) .. end_of(position)];
+        if segment.chars().any(char::is_alphanumeric) { segments.push(segment); }
+    }
+
+    segments
+}
+
+/// Whether two adjacent characters belong to the same word.
+fn joins(previous: Class, next: Class) -> bool {
+    use Class::*;
+
+    matches!((previous, next),
+        (Letter, Letter) | (Letter, Numeric) | (Numeric, Letter) | (Numeric, Numeric)
+        | (Katakana, Katakana)
+        | (Letter, ExtendNumLet) | (Numeric, ExtendNumLet) | (Katakana, ExtendNumLet) | (ExtendNumLet, ExtendNumLet)
+        | (ExtendNumLet, Letter) | (ExtendNumLet, Numeric) | (ExtendNumLet, Katakana)
+    )
+}
+
+fn is_apostrophe(char: char) -> bool {
+    matches!(char, '\'' | '\u{2019}' | '\u{02BC}' | '\u{FF07}')
+}
+
+fn class(char: char) -> Class {
+    match char {
+        '\'' | '.' | '\u{2018}' | '\u{2019}' | '\u{2024}' | '\u{FE52}' | '\u{FF07}' | '\u{FF0E}' => Class::MidNumLet,
+        '\u{B7}' | '\u{387}' | '\u{5F4}' | '\u{2027}' | '\u{FE55}' => Class::MidLetter,
+
+        ',' | ';' | '\u{37E}' | '\u{589}' | '\u{60C}' | '\u{60D}' | '\u{66C}' | '\u{7F8}' | '\u{2044}'
+        | '\u{FE10}' | '\u{FE14}' | '\u{FE50}' | '\u{FE54}' | '\u{FF0C}' | '\u{FF1B}' => Class::MidNum,
+
+        '-' | '\u{2010}' | '\u{2011}' => Class::Hyphen,
+
+        '_' | '\u{202F}' | '\u{203F}' | '\u{2040}' | '\u{2054}' | '\u{FE33}' | '\u{FE34}'
+        | '\u{FE4D}' ..= '\u{FE4F}' | '\u{FF3F}' => Class::ExtendNumLet,
+
+        '\u{3040}' ..= '\u{309F}' // hiragana
+        | '\u{3400}' ..= '\u{4DBF}' | '\u{4E00}' ..= '\u{9FFF}' | '\u{F900}' ..= '\u{FAFF}'
+        | '\u{20000}' ..= '\u{2FA1F}' => Class::Ideograph,
+
+        '\u{30A0}' ..= '\u{30FF}' | '\u{31F0}' ..= '\u{31FF}' | '\u{3031}' ..= '\u{3035}'
+        | '\u{FF66}' ..= '\u{FF9F}' => Class::Katakana,
+
+        // combining marks, joiners, variation selectors and invisible formatting characters
+        '\u{300}' ..= '\u{36F}' | '\u{483}' ..= '\u{489}' | '\u{591}' ..= '\u{5BD}'
+        | '\u{1AB0}' ..= '\u{1AFF}' | '\u{1DC0}' ..= '\u{1DFF}' | '\u{20D0}' ..= '\u{20FF}'
+        | '\u{FE00}' ..= '\u{FE0F}' | '\u{FE20}' ..= '\u{FE2F}'
+        | '\u{E31}' | '\u{E34}' ..= '\u{E3A}' | '\u{E47}' ..= '\u{E4E}' // thai
+        | '\u{EB1}' | '\u{EB4}' ..= '\u{EBC}' | '\u{EC8}' ..= '\u{ECE}' // lao
+        | '\u{17B4}' ..= '\u{17D3}' | '\u{17DD}' // khmer
+        | '\u{102B}' ..= '\u{103E}' | '\u{1056}' ..= '\u{1059}' | '\u{105E}' ..= '\u{1060}' | '\u{1062}' ..= '\u{1064}'
+        | '\u{1067}' ..= '\u{106D}' | '\u{1071}' ..= '\u{1074}' | '\u{1082}' ..= '\u{108D}' | '\u{108F}' | '\u{109A}' ..= '\u{109D}' // myanmar
+        | '\u{AD}' | '\u{200B}' ..= '\u{200D}' | '\u{2060}' | '\u{FEFF}' => Class::Extend,
+
+        char if char.is_numeric() => Class::Numeric,
+        char if char.is_alphabetic() => Class::Letter,
+        _ => Class::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tokenizer, UnicodeTokenizer};
+
+    fn words(text: &str) -> Vec<String> {
+        UnicodeTokenizer::default().words(text)
+    }
+
+    #[test]
+    fn splits_at_spaces_and_punctuation() {
+        assert_eq!(words("Hello, world! Is it (really) over?"), vec![ "hello", "world", "is", "it", "really", "over" ]);
+        assert!(words(" -- ... ").is_empty());
+    }
+
+    #[test]
+    fn keeps_abbreviations() {
+        assert_eq!(words("The U.S. and the U.K."), vec![ "the", "u.s", "and", "the", "u.k" ]);
+
+        let tokenizer = UnicodeTokenizer { case: true, .. UnicodeTokenizer::default() };
+        assert_eq!(tokenizer.words("U.S. Army"), vec![ "U.S", "Army" ]);
+    }
+
+    #[test]
+    fn splits_hyphenated_words_unless_enabled() {
+        assert_eq!(words("Send an e-mail"), vec![ "send", "an", "e", "mail" ]);
+
+        let tokenizer = UnicodeTokenizer { hyphens: true, .. UnicodeTokenizer::default() };
+        assert_eq!(tokenizer.words("Send an e-mail - now"), vec![ "send", "an", "e-mail", "now" ]);
+    }
+
+    #[test]
+    fn removes_words_with_digits_unless_enabled() {
+        assert_eq!(words("3D printers cost 1,000 dollars"), vec![ "printers", "cost", "dollars" ]);
+
+        let tokenizer = UnicodeTokenizer { digits: true, .. UnicodeTokenizer::default() };
+        assert_eq!(tokenizer.words("3D printers cost 1,000 dollars"), vec![ "3d", "printers", "cost", "1,000", "dollars" ]);
+    }
+
+    #[test]
+    fn keeps_contractions_unless_split() {
+        assert_eq!(words("I don't know"), vec![ "i", "don't", "know" ]);
+        assert_eq!(words("I don\u{2019}t know"), vec![ "i", "don't", "know" ]);
+
+        let tokenizer = UnicodeTokenizer { split_contractions: true, .. UnicodeTokenizer::default() };
+        assert_eq!(tokenizer.words("I don't know"), vec![ "i", "don", "'t", "know" ]);
+    }
+
+    #[test]
+    fn splits_ideographs_and_hiragana_into_characters() {
+        assert_eq!(words("我爱北京"), vec![ "我", "爱", "北", "京" ]);
+        assert_eq!(words("カタカナとひらがな"), vec![ "カタカナ", "と", "ひ", "ら", "が", "な" ]);
+    }
+
+    #[test]
+    fn keeps_runs_of_other_scripts_without_spaces() {
+        assert_eq!(words("ภาษาไทย ไม่ดี"), vec![ "ภาษาไทย", "ไม่ดี" ]);
+        assert_eq!(words("ភាសាខ្មែរ"), vec![ "ភាសាខ្មែរ" ]);
+        assert_eq!(words("မြန်မာဘာသာ"), vec![ "မြန်မာဘာသာ" ]);
+    }
+}